@@ -28,8 +28,9 @@ pub struct CompileStatus {
 
 pub trait CastableFunction {
     fn compile(&self, wasi_snapshot_preview1: Vec<u8>) -> Result<CompileStatus, String>;
-    fn compose(&self);
-    fn precompile(&self, target: Option<&str>);
+    /// Compose the component at `component_path` with `dependencies`, returning the path of the composed component
+    fn compose(&self, component_path: &Path, dependencies: &[PathBuf]) -> Result<PathBuf, String>;
+    fn precompile(&self, component_path: &Path, target: &str);
     // FIXME should CastableFunction be responsible for constructing its net path like this?
     fn artifact_path(&self) -> PathBuf;
 }
@@ -77,22 +78,58 @@ pub fn command(matches: Option<&ArgMatches>) {
         function_artifact_path.pop();
         let function_artifact_path = function_artifact_path.to_str().unwrap();
 
+        let mut artifact_paths = vec![castable_function.artifact_path()];
         match castable_function.compile(wasi_snapshot_preview1.clone().to_vec()) {
-            Ok(status) => {
-                let wasm_path_precompiled = PathBuf::from(&format!("{}.bin", status.wasm_path.to_str().unwrap()));
-                if (function.precompiled && status.changed) || (function.precompiled && !wasm_path_precompiled.exists()) {
-                    // TODO set target triple
-                    castable_function.precompile(None);
+            Ok(mut status) => {
+                if !function.components.is_empty() {
+                    let function_dir = project
+                        .service_dir(function.service_name.clone())
+                        .function_dir(function.name.clone());
+                    let dependencies = function
+                        .components
+                        .iter()
+                        .map(|c| function_dir.join(c))
+                        .collect::<Vec<PathBuf>>();
+                    match castable_function.compose(&status.wasm_path, &dependencies) {
+                        Ok(path) => {
+                            status.wasm_path = path;
+                            status.changed = true;
+                        }
+                        Err(e) => fail(format!("Error composing function {}: {}", &function.name, e)),
+                    }
                 }
 
-                let from_path = match function.precompiled {
-                    true => wasm_path_precompiled,
-                    false => status.wasm_path.clone(),
-                };
-
-                std::fs::copy(from_path, castable_function.artifact_path()).unwrap();
+                match function.precompiled {
+                    true => {
+                        // Ship a precompiled artifact per target, plus the component itself so that
+                        // the runtime can fall back to compiling it if none of the artifacts are compatible
+                        let mut wasm_artifact_path = castable_function.artifact_path();
+                        wasm_artifact_path.set_extension("");
+                        for (idx, arch) in function.architectures.iter().enumerate() {
+                            let target = wasm::precompile_target(arch)
+                                .expect("architecture should have been validated by the generator");
+                            let wasm_path_precompiled = wasm::precompiled_path(&status.wasm_path, target);
+                            if status.changed || !wasm_path_precompiled.exists() {
+                                castable_function.precompile(&status.wasm_path, target);
+                            }
+
+                            // The first architecture is the default, loaded by runtimes which don't look for a per-target artifact
+                            if idx == 0 {
+                                std::fs::copy(&wasm_path_precompiled, castable_function.artifact_path()).unwrap();
+                            }
+                            let artifact_path = wasm::precompiled_path(&wasm_artifact_path, target);
+                            std::fs::copy(&wasm_path_precompiled, &artifact_path).unwrap();
+                            artifact_paths.push(artifact_path);
+                        }
+                        std::fs::copy(&status.wasm_path, &wasm_artifact_path).unwrap();
+                        artifact_paths.push(wasm_artifact_path);
+                    }
+                    false => {
+                        std::fs::copy(&status.wasm_path, castable_function.artifact_path()).unwrap();
+                    }
+                }
             },
-            Err(e) => return println!("Error compiling function {}: {}", &function.name, e),
+            Err(e) => fail(format!("Error compiling function {}: {}", &function.name, e)),
         }
 
         // Function archive is only needed for Lambda at this time
@@ -103,11 +140,11 @@ pub fn command(matches: Option<&ArgMatches>) {
             .name()
             .eq(&assemblylift_generator::providers::aws_lambda::provider_name())
         {
-            let mut function_dirs = vec![castable_function.artifact_path()];
-            if "ruby" == function.language.clone().as_str() {
+            let mut function_dirs = artifact_paths;
+            if let Some(interpreter) = &function.interpreter {
                 function_dirs.push(PathBuf::from(format!(
-                    "{}/rubysrc",
-                    &function_artifact_path
+                    "{}/{}",
+                    &function_artifact_path, &interpreter.source_dir
                 )));
             }
             archive::zip_dirs(
@@ -150,3 +187,9 @@ pub fn command(matches: Option<&ArgMatches>) {
     tf.init();
     tf.plan();
 }
+
+/// Stop the cast, so that a function which failed to build can't be deployed
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1)
+}
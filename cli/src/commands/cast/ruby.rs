@@ -5,6 +5,7 @@ use std::rc::Rc;
 
 use path_abs::PathInfo;
 
+use assemblylift_core::runtime_environment;
 use assemblylift_core::wasm;
 use assemblylift_generator::context::Function;
 use assemblylift_generator::projectfs::{NetDir, Project};
@@ -44,7 +45,7 @@ impl RubyFunction {
             net_dir,
             enable_precompile: function.precompiled,
             cpu_compat_mode: function.cpu_compat_mode.clone(),
-            ruby_version: "3.3.0-dev".into(),
+            ruby_version: runtime_environment::RUBY.interpreter.as_ref().unwrap().version.into(),
         }
     }
 }
@@ -153,30 +154,30 @@ impl CastableFunction for RubyFunction {
         Ok(CompileStatus { wasm_path: PathBuf::from(&component_wasm), changed: ruby_changed })
     }
 
-    fn compose(&self) {
-        todo!()
+    // The interpreter component is shared by all Ruby functions, so the composed component is written to the function's net dir
+    fn compose(&self, component_path: &Path, dependencies: &[PathBuf]) -> Result<PathBuf, String> {
+        println!("🧩 > Composing function `{}`...", &self.function_name);
+        let bytes = wasm::compose_component(component_path, dependencies).map_err(|e| format!("{:?}", e))?;
+        let composed_path = self
+            .net_dir
+            .service_dir(&self.service_name.clone())
+            .function_dir(self.function_name.clone())
+            .join(format!("{}.composed.component.wasm", &self.function_name));
+        std::fs::write(&composed_path, bytes).map_err(|e| e.to_string())?;
+        Ok(composed_path)
     }
 
-    // TODO projectfs should handle mapping the precompiled bin path
-    fn precompile(&self, target: Option<&str>) {
+    fn precompile(&self, component_path: &Path, target: &str) {
         println!("⚡️ > Precompiling function `{}`...", &self.function_name);
-        let net_path = self
-            .net_dir
-            .runtime_dir()
-            .to_str()
-            .unwrap()
-            .to_string();
-        let ruby_runtime_path = format!("{}/ruby/{}", net_path, self.ruby_version);
-        let path = format!("{}/ruby-wasm32-wasi/usr/local/bin/ruby.component.wasm", &ruby_runtime_path);
         let bytes = wasm::precompile(
-            Path::new(&path),
-            &target.unwrap_or("x86_64-linux-gnu"),
+            component_path,
+            target,
             &self.cpu_compat_mode.clone(),
         )
         .unwrap();
-        let out_path = format!("{}.bin", path);
+        let out_path = wasm::precompiled_path(component_path, target);
         std::fs::write(&out_path, bytes).unwrap();
-        println!("📄 > Wrote {}", &out_path);
+        println!("📄 > Wrote {}", out_path.display());
     }
 
     fn artifact_path(&self) -> PathBuf {
@@ -10,6 +10,9 @@ use crate::commands::cast::CastableFunction;
 
 use super::CompileStatus;
 
+/// Adapts a reactor module, which has no `main` to export as `wasi:cli/run`
+static WASI_SNAPSHOT_PREVIEW1_REACTOR: &[u8] = include_bytes!("wasm/wasi_snapshot_preview1.reactor.wasm");
+
 pub struct RustFunction {
     project: Rc<Project>,
     service_name: String,
@@ -45,7 +48,43 @@ impl RustFunction {
         }
     }
 
+    fn manifest_path(&self) -> PathBuf {
+        PathBuf::from(format!(
+            "{}/Cargo.toml",
+            self.project
+                .clone()
+                .service_dir(self.service_name.clone())
+                .function_dir(self.function_name.clone())
+                .to_str()
+                .unwrap()
+        ))
+    }
+
+    /// The name of the function's library target, if it is built as a reactor (a `cdylib`)
+    fn reactor_lib_name(&self) -> Option<String> {
+        let manifest: toml::Value = std::fs::read_to_string(self.manifest_path())
+            .ok()
+            .and_then(|s| toml::from_str(&s).ok())?;
+        let lib = manifest.get("lib")?;
+        let is_cdylib = lib
+            .get("crate-type")
+            .and_then(|t| t.as_array())
+            .map_or(false, |types| types.iter().any(|t| t.as_str() == Some("cdylib")));
+        if !is_cdylib {
+            return None;
+        }
+        let name = lib
+            .get("name")
+            .or_else(|| manifest.get("package").and_then(|p| p.get("name")))
+            .and_then(|n| n.as_str())
+            .unwrap_or(&self.function_name);
+        Some(name.replace('-', "_"))
+    }
+
     pub fn source_wasm_path(&self) -> String {
+        let module_name = self
+            .reactor_lib_name()
+            .unwrap_or_else(|| self.function_name.clone());
         let copy_from = format!(
             "{}/target/{}/{}/{}.wasm",
             self.project
@@ -56,7 +95,7 @@ impl RustFunction {
                 .unwrap(),
             self.target,
             self.mode,
-            self.function_name,
+            module_name,
         );
         let copy_from = match std::fs::metadata(&copy_from) {
             Ok(_) => copy_from,
@@ -65,7 +104,7 @@ impl RustFunction {
                 self.project.clone().dir().to_str().unwrap(),
                 self.target,
                 self.mode,
-                self.function_name,
+                module_name,
             ),
         };
         copy_from
@@ -74,15 +113,7 @@ impl RustFunction {
 
 impl CastableFunction for RustFunction {
     fn compile(&self, wasi_snapshot_preview1: Vec<u8>) -> Result<CompileStatus, String> {
-        let manifest_path = PathBuf::from(format!(
-            "{}/Cargo.toml",
-            self.project
-                .clone()
-                .service_dir(self.service_name.clone())
-                .function_dir(self.function_name.clone())
-                .to_str()
-                .unwrap()
-        ));
+        let manifest_path = self.manifest_path();
 
         println!("🛠️ > Compiling function `{}`...", self.function_name.clone());
         let cargo_build = std::process::Command::new("cargo")
@@ -107,7 +138,11 @@ impl CastableFunction for RustFunction {
         component_path.set_extension("component.wasm");
         {
             let module = std::fs::read(self.source_wasm_path()).unwrap();
-            let component = wasm::make_wasi_component(module, wasi_snapshot_preview1.as_slice())
+            let adapter = match self.reactor_lib_name() {
+                Some(_) => WASI_SNAPSHOT_PREVIEW1_REACTOR,
+                None => wasi_snapshot_preview1.as_slice(),
+            };
+            let component = wasm::make_wasi_component(module, adapter)
                 .expect("unable to make component of the provided module");
             std::fs::write(component_path.clone(), component).unwrap();
         }
@@ -115,21 +150,24 @@ impl CastableFunction for RustFunction {
         Ok(CompileStatus { wasm_path: component_path.clone(), changed: true })
     }
 
-    fn compose(&self) {
-        todo!()
+    fn compose(&self, component_path: &Path, dependencies: &[PathBuf]) -> Result<PathBuf, String> {
+        println!("🧩 > Composing function `{}`...", &self.function_name);
+        let bytes = wasm::compose_component(component_path, dependencies).map_err(|e| format!("{:?}", e))?;
+        // Written alongside the function's own component, which is left as built
+        let composed_path = component_path.with_file_name(format!("{}.composed.component.wasm", &self.function_name));
+        std::fs::write(&composed_path, bytes).map_err(|e| e.to_string())?;
+        Ok(composed_path)
     }
 
-    fn precompile(&self, target: Option<&str>) {
+    fn precompile(&self, component_path: &Path, target: &str) {
         println!("⚡️ > Precompiling function `{}`...", &self.function_name);
-        let mut path = PathBuf::from(self.source_wasm_path());
-        path.set_extension("component.wasm");
         let bytes = wasm::precompile(
-            Path::new(&path),
-            &target.unwrap_or("x86_64-linux-gnu"),
+            component_path,
+            target,
             &self.cpu_compat_mode.clone(),
         )
         .unwrap();
-        path.set_extension("wasm.bin");
+        let path = wasm::precompiled_path(component_path, target);
         std::fs::write(&path, bytes).unwrap();
         println!("📄 > Wrote {}", path.to_str().unwrap());
     }
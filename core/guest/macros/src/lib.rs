@@ -3,10 +3,20 @@ use syn::{parse2, ItemFn};
 
 #[proc_macro_attribute]
 pub fn handler(
-    _args: proc_macro::TokenStream,
+    args: proc_macro::TokenStream,
     stream: proc_macro::TokenStream,
 ) -> proc_macro::TokenStream {
     let input: ItemFn = parse2(stream.into()).expect("could not parse token stream");
+    // `#[handler(reactor)]` also exports the handler, so the runtime can call it on a warm instance
+    let reactor = match args.to_string().as_str() {
+        "" => false,
+        "reactor" => true,
+        _ => {
+            return proc_macro::TokenStream::from(quote! {
+                compile_error!("expected `#[handler]` or `#[handler(reactor)]`");
+            })
+        }
+    };
     let block_statements = &input.block.stmts;
     let name = &input.sig.ident;
     let _ret = &input.sig.output;
@@ -17,9 +27,24 @@ pub fn handler(
         });
     }
 
+    // A reactor is built as a cdylib without `main`; its handler is called through the `handler` export
+    let entrypoint = match reactor {
+        true => quote! {
+            #[no_mangle]
+            pub fn __asml_handler(ctx: FunctionContext) {
+                __handler(ctx)
+            }
+        },
+        false => quote! {
+            fn main() {
+                __handler(FunctionContext { input: asml_rt::get_input() })
+            }
+        },
+    };
+
     proc_macro::TokenStream::from(quote! {
         use assemblylift_core_guest::asml_rt;
-        use assemblylift_core_guest::direct_executor;
+        use assemblylift_core_guest::executor;
         // use assemblylift_core_guest::export_command;
         use assemblylift_core_guest::FunctionContext;
         // use assemblylift_core_guest::command::Command;
@@ -32,12 +57,10 @@ pub fn handler(
         // }
         // export_command!(Cmd);
         fn __handler(ctx: FunctionContext) {
-            direct_executor::run_spinning(async {
+            executor::block_on(async {
                 #(#block_statements)*
             });
         }
-        fn main() {
-            __handler(FunctionContext { input: asml_rt::get_input() })
-        }
+        #entrypoint
     })
 }
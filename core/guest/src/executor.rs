@@ -0,0 +1,55 @@
+//! A single-threaded executor for a guest's handler. While the handler is waiting on the host, e.g.
+//! for an IOmod call to respond, the guest blocks on `wasi:io/poll` rather than polling in a loop.
+//!
+//! Futures which wait on the host register a pollable with `wait_on` before returning `Pending`;
+//! the executor blocks until one of the registered pollables is ready, then polls the handler again.
+//! A future may instead wake its task before returning `Pending`. A task which is pending but
+//! has neither registered a pollable nor been woken could never complete, so the executor panics.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use crate::assemblylift::wasi::io::poll::{self, Pollable};
+
+thread_local! {
+    static POLLABLES: RefCell<Vec<Pollable>> = RefCell::new(Vec::new());
+}
+
+/// Have the executor block on `pollable` before polling the current task again
+pub fn wait_on(pollable: Pollable) {
+    POLLABLES.with(|p| p.borrow_mut().push(pollable));
+}
+
+/// Run `future` to completion on the current thread
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = Box::pin(future);
+    let woken = Arc::new(Woken(AtomicBool::new(false)));
+    let waker = Waker::from(woken.clone());
+    let mut cx = Context::from_waker(&waker);
+    loop {
+        if let Poll::Ready(output) = Pin::as_mut(&mut future).poll(&mut cx) {
+            POLLABLES.with(|p| p.borrow_mut().clear());
+            return output;
+        }
+        let pollables = POLLABLES.with(|p| p.take());
+        if !pollables.is_empty() {
+            poll::poll(&pollables.iter().collect::<Vec<&Pollable>>());
+            woken.0.store(false, Ordering::SeqCst);
+        } else if !woken.0.swap(false, Ordering::SeqCst) {
+            panic!("the handler is pending without a pollable or wake-up; it would never complete");
+        }
+    }
+}
+
+/// Records that the task was woken while it was being polled
+struct Woken(AtomicBool);
+
+impl Wake for Woken {
+    fn wake(self: Arc<Self>) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+}
@@ -3,19 +3,23 @@ extern crate assemblylift_core_guest_macros;
 use std::collections::HashMap;
 use std::fmt;
 
+use base64::{engine::general_purpose, Engine as _};
 pub use direct_executor;
 use serde::{Deserialize, Serialize};
 pub use wit_bindgen;
 
 pub use assemblylift::akkoro::assemblylift::asml_io;
 pub use assemblylift::akkoro::assemblylift::asml_rt;
+pub use assemblylift::wasi;
 pub use assemblylift_core_guest_macros::handler;
 // pub use command::wasi;
 
 pub mod assemblylift;
 // pub mod command;
+pub mod executor;
 pub mod jwt;
 pub mod opa;
+pub mod reactor;
 pub mod secrets;
 
 pub struct FunctionContext {
@@ -28,16 +32,43 @@ impl FunctionContext {
         asml_rt::log(asml_rt::LogLevel::Info, clap::crate_name!(), &message)
     }
 
+    /// The W3C trace id of the current invocation, if it is being traced
+    pub fn trace_id() -> Option<String> {
+        asml_rt::get_trace_id()
+    }
+
     pub fn success(response: String) {
-        asml_rt::success(&response.as_bytes().to_vec())
+        match reactor::handling() {
+            true => reactor::set_response(asml_rt::HttpResponse {
+                status: 200,
+                headers: Vec::new(),
+                body: response.into_bytes(),
+            }),
+            false => asml_rt::success(&response.as_bytes().to_vec()),
+        }
     }
 
     pub fn failure(response: String) {
-        asml_rt::failure(&response.as_bytes().to_vec())
+        match reactor::handling() {
+            true => reactor::set_response(asml_rt::HttpResponse {
+                status: 500,
+                headers: Vec::new(),
+                body: response.into_bytes(),
+            }),
+            false => asml_rt::failure(&response.as_bytes().to_vec()),
+        }
+    }
+
+    pub fn respond(response: HttpResponse) {
+        match reactor::handling() {
+            true => reactor::set_response(response.into()),
+            false => asml_rt::respond(&response.into()),
+        }
     }
 }
 
 pub type StatusCode = u16;
+// The Serialize impl produces the API Gateway format that was returned via `success` before `respond`
 #[derive(Serialize, Deserialize)]
 pub struct HttpResponse {
     #[serde(rename = "isBase64Encoded")]
@@ -48,6 +79,52 @@ pub struct HttpResponse {
     body: String,
 }
 
+impl From<HttpResponse> for asml_rt::HttpResponse {
+    fn from(response: HttpResponse) -> Self {
+        let body = match response.is_base64_encoded {
+            true => general_purpose::STANDARD
+                .decode(&response.body)
+                .unwrap_or_else(|_| response.body.into_bytes()),
+            false => response.body.into_bytes(),
+        };
+        Self {
+            status: response.status_code,
+            headers: response.headers.into_iter().collect(),
+            body,
+        }
+    }
+}
+
+/// A response whose body is sent to the client in chunks, as it is written.
+/// The response is finished when `finish` is called or it is dropped.
+pub struct StreamingResponse {
+    finished: bool,
+}
+
+impl StreamingResponse {
+    pub fn begin(status: StatusCode, headers: Vec<(String, String)>) -> Self {
+        asml_rt::begin_response(status, &headers);
+        Self { finished: false }
+    }
+
+    pub fn write(&mut self, chunk: &[u8]) {
+        asml_rt::write_chunk(&chunk.to_vec())
+    }
+
+    pub fn finish(mut self) {
+        self.finished = true;
+        asml_rt::finish_response()
+    }
+}
+
+impl Drop for StreamingResponse {
+    fn drop(&mut self) {
+        if !self.finished {
+            asml_rt::finish_response()
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct HttpError {
     pub code: StatusCode,
@@ -118,43 +195,32 @@ impl HttpResponse {
 #[macro_export]
 macro_rules! http_ok {
     ($response:expr) => {
-        FunctionContext::success(
-            serde_json::to_string(&HttpResponse::ok(
-                serde_json::to_string(&$response).unwrap(),
-                None,
-                false,
-                false,
-            ))
-            .unwrap(),
-        );
+        FunctionContext::respond(HttpResponse::ok(
+            serde_json::to_string(&$response).unwrap(),
+            None,
+            false,
+            false,
+        ));
     };
 
     ($response:expr, $type:expr, $isb64:expr, $isgzip:expr) => {
-        FunctionContext::success(
-            serde_json::to_string(&HttpResponse::ok($response, $type, $isb64, $isgzip)).unwrap(),
-        );
+        FunctionContext::respond(HttpResponse::ok($response, $type, $isb64, $isgzip));
     };
 }
 
 #[macro_export]
 macro_rules! http_error {
     ($message:expr) => {
-        FunctionContext::success(
-            serde_json::to_string(&HttpResponse::error($message, HttpErrorCode::FunctionError))
-                .unwrap(),
-        );
+        FunctionContext::respond(HttpResponse::error($message, HttpErrorCode::FunctionError));
     };
 }
 
 #[macro_export]
 macro_rules! http_not_found {
     ($resource_name:expr) => {
-        FunctionContext::success(
-            serde_json::to_string(&HttpResponse::error(
-                format!("missing resource {:?}", $resource_name),
-                HttpErrorCode::NotFound,
-            ))
-            .unwrap(),
-        );
+        FunctionContext::respond(HttpResponse::error(
+            format!("missing resource {:?}", $resource_name),
+            HttpErrorCode::NotFound,
+        ));
     };
 }
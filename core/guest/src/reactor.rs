@@ -0,0 +1,73 @@
+//! Reactor functions export `akkoro:assemblylift/handler`. The runtime instantiates them once and
+//! calls `handle` for each invocation, instead of running `main` on a fresh instance.
+//!
+//! A reactor is built as a `cdylib` with this crate's `reactor` feature, and marks its handler with
+//! `#[handler(reactor)]`; the macro defines `__asml_handler` instead of `main`. While the handler runs,
+//! responses passed to `FunctionContext` become the return value of `handle` rather than calls into
+//! the runtime.
+
+use std::cell::{Cell, RefCell};
+
+use crate::asml_rt;
+use crate::FunctionContext;
+
+thread_local! {
+    static HANDLING: Cell<bool> = Cell::new(false);
+    static RESPONSE: RefCell<Option<asml_rt::HttpResponse>> = RefCell::new(None);
+}
+
+/// True while a reactor's handler is running
+pub(crate) fn handling() -> bool {
+    HANDLING.with(|h| h.get())
+}
+
+/// Keep `response` as the result of the running handler
+pub(crate) fn set_response(response: asml_rt::HttpResponse) {
+    RESPONSE.with(|r| *r.borrow_mut() = Some(response));
+}
+
+/// Run `handler` for one invocation, returning the response it gave
+pub fn handle(input: Vec<u8>, handler: fn(FunctionContext)) -> asml_rt::HttpResponse {
+    HANDLING.with(|h| h.set(true));
+    handler(FunctionContext { input });
+    HANDLING.with(|h| h.set(false));
+    RESPONSE
+        .with(|r| r.borrow_mut().take())
+        .unwrap_or_else(|| asml_rt::HttpResponse {
+            status: 500,
+            headers: Vec::new(),
+            body: "No Response".as_bytes().to_vec(),
+        })
+}
+
+#[cfg(feature = "reactor")]
+pub use export::Reactor;
+
+#[cfg(feature = "reactor")]
+mod export {
+    use crate::assemblylift::exports::akkoro::assemblylift::handler::{Guest, HttpResponse};
+    use crate::FunctionContext;
+
+    extern "Rust" {
+        // Defined by `#[handler(reactor)]` in the function's crate
+        fn __asml_handler(ctx: FunctionContext);
+    }
+
+    fn call_handler(ctx: FunctionContext) {
+        unsafe { __asml_handler(ctx) }
+    }
+
+    /// The `handler` export of the `reactor` world
+    pub struct Reactor;
+
+    impl Guest for Reactor {
+        fn handle(input: Vec<u8>) -> HttpResponse {
+            let response = super::handle(input, call_handler);
+            HttpResponse {
+                status: response.status,
+                headers: response.headers,
+                body: response.body,
+            }
+        }
+    }
+}
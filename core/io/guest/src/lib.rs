@@ -1,3 +1,4 @@
+use std::fmt;
 use std::future::Future;
 use std::marker::PhantomData;
 use std::pin::Pin;
@@ -6,7 +7,7 @@ use std::task::{Context, Poll, Waker};
 use serde::{de::DeserializeOwned, Deserialize};
 
 use assemblylift_core_guest::asml_rt::LogLevel;
-use assemblylift_core_guest::{asml_io, asml_rt};
+use assemblylift_core_guest::{asml_io, asml_rt, executor};
 
 #[derive(Clone)]
 /// A handle implementing `std::future::Future` for an in-flight IOmod call
@@ -30,30 +31,78 @@ impl<'a, R> Future for Io<'_, R>
 where
     R: DeserializeOwned,
 {
-    type Output = R;
+    type Output = Result<R, IoError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         match asml_io::poll(self.id) {
-            Ok(res) => Poll::Ready(
-                read_response::<Self::Output>(std::str::from_utf8(&*res).unwrap()).unwrap(),
-            ),
-            _ => {
+            Ok(res) => Poll::Ready(read_response::<R>(&res)),
+            Err(asml_io::PollError::NotReady) => {
                 self.waker = Box::new(Some(cx.waker().clone()));
+                // Block the guest until the host has the response, rather than polling it again straight away
+                executor::wait_on(asml_io::subscribe(self.id));
                 Poll::Pending
             }
+            Err(asml_io::PollError::TimedOut) => Poll::Ready(Err(IoError::TimedOut)),
+            Err(asml_io::PollError::InvalidIoid) => Poll::Ready(Err(IoError::InvalidIoid)),
+            Err(asml_io::PollError::Failed(err)) => Poll::Ready(Err(IoError::Failed(err))),
         }
     }
 }
 
-fn read_response<'a, T>(res: &str) -> Option<T>
+/// Why an IOmod call did not produce a response
+#[derive(Clone, Debug)]
+pub enum IoError {
+    /// The call took longer than its timeout
+    TimedOut,
+    /// The call is unknown to the host, e.g. because it was released or cancelled
+    InvalidIoid,
+    /// The call completed with an error
+    Failed(asml_io::IoError),
+    /// The call's response could not be read as the expected type
+    InvalidResponse(String),
+}
+
+impl IoError {
+    /// True if the same call might succeed if made again
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            IoError::TimedOut => true,
+            IoError::Failed(asml_io::IoError::Iomod(err)) => err.retryable,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IoError::TimedOut => write!(f, "the call timed out"),
+            IoError::InvalidIoid => write!(f, "the call is unknown or was cancelled"),
+            IoError::Failed(asml_io::IoError::CoordsNotFound) => {
+                write!(f, "no IOmod has these coordinates")
+            }
+            IoError::Failed(asml_io::IoError::InvalidCoords) => {
+                write!(f, "the coordinates are invalid")
+            }
+            IoError::Failed(asml_io::IoError::InvalidIoid) => {
+                write!(f, "the call is unknown or was cancelled")
+            }
+            IoError::Failed(asml_io::IoError::Iomod(err)) => {
+                write!(f, "{}: {}", err.code, err.message)
+            }
+            IoError::InvalidResponse(why) => write!(f, "could not read the response: {}", why),
+        }
+    }
+}
+
+impl std::error::Error for IoError {}
+
+fn read_response<T>(res: &[u8]) -> Result<T, IoError>
 where
     T: DeserializeOwned,
 {
-    match serde_json::from_str(res) {
-        Ok(response) => Some(response),
-        Err(why) => {
-            asml_rt::log(LogLevel::Error, "core::io::read_response", &why.to_string());
-            None
-        }
-    }
+    serde_json::from_slice(res).map_err(|why| {
+        asml_rt::log(LogLevel::Error, "core::io::read_response", &why.to_string());
+        IoError::InvalidResponse(why.to_string())
+    })
 }
@@ -2,6 +2,7 @@
 
 use std::cell::RefCell;
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
 use capnp::capability::Promise;
@@ -10,22 +11,124 @@ use futures::future::BoxFuture;
 use futures_util::TryFutureExt;
 use tokio::sync::mpsc;
 
-use crate::iomod_capnp::{agent, iomod};
+use crate::iomod_capnp::{agent, iomod, iomod_error};
 
 pub mod iomod_capnp;
 pub mod macros;
 pub mod package;
 pub mod registry;
 
+tokio::task_local! {
+    static TRACEPARENT: Option<String>;
+}
+
+/// The W3C trace context of the IOmod call being handled, if it is being traced
+pub fn traceparent() -> Option<String> {
+    TRACEPARENT.try_with(|traceparent| traceparent.clone()).ok().flatten()
+}
+
+/// Run an IOmod call with its trace context, which it can read with `traceparent()`
+pub async fn with_traceparent<F: std::future::Future>(traceparent: Option<String>, call: F) -> F::Output {
+    TRACEPARENT.scope(traceparent, call).await
+}
+
+/// An error returned by an IOmod call, which is passed on to the guest which made the call
+#[derive(Clone, Debug, PartialEq)]
+pub struct IomodError {
+    /// Machine-readable, e.g. `not-found`
+    pub code: String,
+    pub message: String,
+    /// True if the same call might succeed if made again
+    pub retryable: bool,
+}
+
+impl IomodError {
+    pub fn new(code: &str, message: &str, retryable: bool) -> Self {
+        Self {
+            code: code.to_string(),
+            message: message.to_string(),
+            retryable,
+        }
+    }
+
+    fn read(reader: iomod_error::Reader) -> capnp::Result<Self> {
+        Ok(Self {
+            code: reader.get_code()?.to_string(),
+            message: reader.get_message()?.to_string(),
+            retryable: reader.get_retryable(),
+        })
+    }
+
+    fn write(&self, mut builder: iomod_error::Builder) {
+        builder.set_code(&self.code);
+        builder.set_message(&self.message);
+        builder.set_retryable(self.retryable);
+    }
+}
+
+impl fmt::Display for IomodError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for IomodError {}
+
+pub type CallResult = Result<Vec<u8>, IomodError>;
+
+/// Read the result of an IOmod call from its RPC response
+pub fn read_call_result(results: agent::invoke_results::Reader) -> capnp::Result<CallResult> {
+    // Responses from IOmods which predate `error` never set it
+    if results.has_error() {
+        return Ok(Err(IomodError::read(results.get_error()?)?));
+    }
+    Ok(Ok(Vec::from(results.get_result()?)))
+}
+
+/// Write the result of an IOmod call to its RPC response
+pub fn write_call_result(result: &CallResult, mut results: iomod::invoke_results::Builder) {
+    match result {
+        Ok(value) => results.set_result(value),
+        Err(error) => error.write(results.init_error()),
+    }
+}
+
+/// The return type of an IOmod call; calls may return their response, or a `CallResult`
+pub trait IntoCallResult {
+    fn into_call_result(self) -> CallResult;
+}
+
+impl IntoCallResult for Vec<u8> {
+    fn into_call_result(self) -> CallResult {
+        Ok(self)
+    }
+}
+
+impl IntoCallResult for CallResult {
+    fn into_call_result(self) -> CallResult {
+        self
+    }
+}
+
+#[doc(hidden)]
+pub fn box_call<'a, F, R>(call: F) -> BoxFuture<'a, CallResult>
+where
+    F: std::future::Future<Output = R> + Send + 'a,
+    R: IntoCallResult,
+{
+    Box::pin(async move { call.await.into_call_result() })
+}
+
 pub struct CallRequest {
     pub coords: String,
     pub input: Vec<u8>,
+    pub traceparent: Option<String>,
     pub responder: mpsc::Sender<CallResponse>,
 }
 
 pub struct CallResponse {
     pub coords: String,
-    pub payload: Vec<u8>,
+    pub result: CallResult,
 }
 
 pub type CallChannel = (mpsc::Sender<CallRequest>, mpsc::Receiver<CallRequest>);
@@ -34,14 +137,14 @@ pub type Call<F> = fn(Vec<u8>) -> F;
 
 pub struct CallPtr<F>
 where
-    F: std::future::Future<Output = Vec<u8>> + Send,
+    F: std::future::Future<Output = CallResult> + Send,
 {
     call: Call<F>,
 }
 
 impl<F> CallPtr<F>
 where
-    F: std::future::Future<Output = Vec<u8>> + Send,
+    F: std::future::Future<Output = CallResult> + Send,
 {
     pub fn new(call: Call<F>) -> Self {
         Self { call }
@@ -49,7 +152,7 @@ where
 }
 
 pub struct CallMap<'a> {
-    pub map: HashMap<&'a str, CallPtr<BoxFuture<'a, Vec<u8>>>>,
+    pub map: HashMap<&'a str, CallPtr<BoxFuture<'a, CallResult>>>,
 }
 
 impl<'a> CallMap<'a> {
@@ -59,9 +162,15 @@ impl<'a> CallMap<'a> {
         }
     }
 
-    pub fn get(&self, coords: String, with_input: Vec<u8>) -> BoxFuture<'a, Vec<u8>> {
-        let call = self.map[coords.as_str()].call;
-        call(with_input)
+    pub fn get(&self, coords: String, with_input: Vec<u8>) -> BoxFuture<'a, CallResult> {
+        match self.map.get(coords.as_str()) {
+            Some(call) => (call.call)(with_input),
+            None => Box::pin(futures::future::ready(Err(IomodError::new(
+                "method-not-found",
+                &format!("no method {}", coords),
+                false,
+            )))),
+        }
     }
 }
 
@@ -86,6 +195,13 @@ impl iomod::Server for Iomod {
         Promise::from_future(async move {
             let coords = params.get().unwrap().get_coordinates().unwrap().to_owned();
             let input = params.get().unwrap().get_input().unwrap();
+            let traceparent = params
+                .get()
+                .unwrap()
+                .get_traceparent()
+                .ok()
+                .filter(|traceparent| !traceparent.is_empty())
+                .map(String::from);
 
             let mut channel: (mpsc::Sender<CallResponse>, mpsc::Receiver<CallResponse>) =
                 mpsc::channel(100);
@@ -93,13 +209,16 @@ impl iomod::Server for Iomod {
             tx.send(CallRequest {
                 coords,
                 input: Vec::from(input),
+                traceparent,
                 responder: channel.0.clone(),
             })
             .and_then(|_| async move {
                 // wait for response from executor thread
-                if let Some(response) = channel.1.recv().await {
-                    results.get().set_result(response.payload.as_slice());
-                }
+                let result = match channel.1.recv().await {
+                    Some(response) => response.result,
+                    None => Err(IomodError::new("no-response", "the call was dropped", true)),
+                };
+                write_call_result(&result, results.get());
 
                 Ok(())
             })
@@ -140,13 +259,50 @@ impl agent::Server for Agent {
             invoke
                 .get()
                 .set_input(params.get().unwrap().get_input().unwrap());
+            if let Ok(traceparent) = params.get().unwrap().get_traceparent() {
+                invoke.get().set_traceparent(traceparent);
+            }
 
-            let invoke_response = invoke.send().promise.await.unwrap();
-            results
-                .get()
-                .set_result(invoke_response.get().unwrap().get_result().unwrap());
+            // Failures are returned to the registry, which answers the caller with an error
+            let invoke_response = invoke.send().promise.await?;
+            let response = invoke_response.get()?;
+            results.get().set_result(response.get_result()?);
+            if response.has_error() {
+                results.get().set_error(response.get_error()?)?;
+            }
 
             Ok(())
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use capnp::message::{Builder, ReaderOptions};
+    use capnp::serialize;
+
+    use super::{read_call_result, write_call_result, CallResult, IomodError};
+    use crate::iomod_capnp::{agent, iomod};
+
+    /// Write `result` as the IOmod's response, and read it back as the agent receives it
+    fn round_trip(result: &CallResult) -> CallResult {
+        let mut message = Builder::new_default();
+        write_call_result(result, message.init_root::<iomod::invoke_results::Builder>());
+        let mut bytes = Vec::new();
+        serialize::write_message(&mut bytes, &message).unwrap();
+
+        let message = serialize::read_message(&bytes[..], ReaderOptions::new()).unwrap();
+        read_call_result(message.get_root::<agent::invoke_results::Reader>().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_call_result() {
+        assert_eq!(Ok(b"{}".to_vec()), round_trip(&Ok(b"{}".to_vec())));
+
+        let error = IomodError::new("not-found", "no such bucket", false);
+        assert_eq!(Err(error.clone()), round_trip(&Err(error)));
+        // An error with an empty code & message is still an error
+        let error = IomodError::new("", "", true);
+        assert_eq!(Err(error.clone()), round_trip(&Err(error)));
+    }
+}
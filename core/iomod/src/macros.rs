@@ -18,7 +18,10 @@ macro_rules! iomod {
         let name = stringify!($name);
 
         let iomod_coords = format!("{}.{}.{}", org, ns, name);
-        println!("Starting AssemblyLift IO module {}", iomod_coords);
+        // The runtime sets the version from the IOmod's package manifest; otherwise it is the crate's version
+        let iomod_version = std::env::var("ASML_IOMOD_VERSION")
+            .unwrap_or_else(|_| String::from(env!("CARGO_PKG_VERSION")));
+        println!("Starting AssemblyLift IO module {}@{}", iomod_coords, iomod_version);
 
         let mut call_map: CallMap = $crate::__calls!($calls);
         let mut call_channel: CallChannel = mpsc::channel(100);
@@ -48,6 +51,7 @@ macro_rules! iomod {
                     .get()
                     .set_iomod(capnp_rpc::new_client(Iomod::new(call_channel.0.clone())));
                 register.get().set_coordinates(iomod_coords.as_str());
+                register.get().set_version(iomod_version.as_str());
                 register.send().promise.await.unwrap();
 
                 let call_task = tokio::task::spawn_local(async move {
@@ -55,13 +59,13 @@ macro_rules! iomod {
                         let coords = call.coords.as_str();
                         let call_ptr = call_map.get(String::from(coords), call.input);
 
-                        let response = call_ptr.await;
+                        let result = $crate::with_traceparent(call.traceparent.clone(), call_ptr).await;
 
                         if let Err(why) = call
                             .responder
                             .send(CallResponse {
                                 coords: String::from(coords),
-                                payload: response,
+                                result,
                             })
                             .await
                         {
@@ -83,7 +87,8 @@ macro_rules! __calls {
         let mut call_map = CallMap::new();
         $(
             let call_name = stringify!($call_name);
-            call_map.map.insert(call_name, CallPtr::new($call));
+            // A call may return its response bytes, or a `CallResult` to report an error
+            call_map.map.insert(call_name, CallPtr::new(|input| $crate::box_call(($call)(input))));
         )*
         call_map
     }};
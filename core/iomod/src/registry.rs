@@ -6,21 +6,25 @@
 //! services call invocations to registered IOmods via MPSC receiver (sent from `Threader`).
 
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt;
 use std::rc::Rc;
 use std::sync::Arc;
+use std::time::Duration;
 
 use capnp::capability::Promise;
 use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
 use futures::{AsyncReadExt, FutureExt, TryFutureExt};
+use once_cell::sync::Lazy;
+use prometheus::{register_int_gauge, IntGauge};
+use semver::{Version, VersionReq};
 use tokio::net::TcpListener;
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
 pub use tokio::sync::mpsc::channel as registry_channel;
-use tracing::{error, info};
+use tracing::{debug, error, info, warn};
 
 use crate::iomod_capnp::{agent, iomod, registry};
-use crate::Agent;
+use crate::{read_call_result, Agent, CallResult, IomodError};
 
 pub type RegistryTx = mpsc::Sender<RegistryChannelMessage>;
 pub type RegistryRx = mpsc::Receiver<RegistryChannelMessage>;
@@ -28,8 +32,37 @@ pub type RegistryChannel = (RegistryTx, RegistryRx);
 
 pub type ClientPair = (iomod::Client, agent::Client);
 
+/// Error code of a call to coordinates no IOmod has registered at
+pub const COORDS_NOT_FOUND: &str = "coords-not-found";
+/// Error code of a call whose version requirement can't be parsed
+pub const INVALID_COORDS: &str = "invalid-coords";
+/// Error code of a call which could not reach its IOmod
+pub const UNAVAILABLE: &str = "unavailable";
+
+/// How long a call to coordinates no matching IOmod has registered at waits for one to register, before
+/// failing with `COORDS_NOT_FOUND`. Set with `ASML_IOMOD_REGISTRATION_WAIT_MS`; by default calls fail immediately.
+pub static REGISTRATION_WAIT: Lazy<Duration> = Lazy::new(|| {
+    std::env::var("ASML_IOMOD_REGISTRATION_WAIT_MS")
+        .ok()
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .map(Duration::from_millis)
+        .unwrap_or(Duration::ZERO)
+});
+
+/// Number of open RPC connections from IOmods
+pub static REGISTRY_CONNECTIONS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("asml_registry_connections", "Open IOmod connections to the registry").unwrap()
+});
+
+/// Number of IOmod versions registered with the registry
+pub static REGISTRY_IOMODS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("asml_registry_iomods", "IOmods registered with the registry").unwrap()
+});
+
 pub struct Registry {
     modules: ModuleMap,
+    /// Notified whenever an IOmod registers
+    registered: Rc<Notify>,
 }
 
 #[derive(Debug)]
@@ -57,10 +90,18 @@ pub struct RegistryChannelMessage {
     pub method_name: String,
     pub payload_type: &'static str,
     pub payload: Vec<u8>,
+    /// Semver requirement of the IOmod version to call, e.g. `^1.2`; the latest registered version if unset.
+    /// Set on a response to the version which handled the call.
+    pub version: Option<String>,
+    /// W3C trace context of the IOmod call, if it is being traced
+    pub traceparent: Option<String>,
+    /// Set on a response if the call failed, in which case the payload is empty
+    pub error: Option<IomodError>,
     pub responder: Option<RegistryTx>,
 }
 
-pub type ModuleMap = Arc<Box<RefCell<HashMap<String, agent::Client>>>>;
+/// Registered IOmods by coordinates, and then by version (`None` if the IOmod didn't register one)
+pub type ModuleMap = Arc<Box<RefCell<HashMap<String, BTreeMap<Option<Version>, agent::Client>>>>>;
 
 pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
     std::thread::spawn(|| {
@@ -68,12 +109,14 @@ pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
 
         tokio::task::LocalSet::new().block_on(&mut rt, async {
             let modules: ModuleMap = Arc::new(Box::new(RefCell::new(HashMap::new())));
+            let registered = Rc::new(Notify::new());
 
             let rpc_modules = modules.clone();
+            let rpc_registered = registered.clone();
             let rpc_task = tokio::task::spawn_local(async move {
                 let listener = TcpListener::bind("0.0.0.0:13555").await.unwrap();
                 let registry_client: registry::Client =
-                    capnp_rpc::new_client(Registry::new(rpc_modules));
+                    capnp_rpc::new_client(Registry::new(rpc_modules, rpc_registered));
 
                 while let Ok((stream, _)) = listener.accept().await {
                     stream.set_nodelay(true).unwrap();
@@ -91,46 +134,26 @@ pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
                     let rpc_system =
                         RpcSystem::new(Box::new(rpc_network), Some(registry_client.clone().client));
 
+                    REGISTRY_CONNECTIONS.inc();
                     tokio::task::spawn_local(Box::pin(
                         rpc_system
                             .map_err(|e| println!("error: {:?}", e))
-                            .map(|_| ()),
+                            .map(|_| REGISTRY_CONNECTIONS.dec()),
                     ));
                 }
             });
 
             let rx_modules = modules.clone();
+            let rx_registered = registered.clone();
             let rx_task = tokio::task::spawn_local(async move {
                 while let Some(msg) = rx.recv().await {
-                    let responder = msg.responder.unwrap();
-                    let coords = msg.iomod_coords;
-                    let method = msg.method_name;
-                    let input = msg.payload.as_slice();
-
-                    let modules = RefCell::borrow(&rx_modules);
-                    match modules.get(&coords) {
-                        Some(agent) => {
-                            info!("invoking call @ {}.{}", coords.clone(), method.clone());
-                            let mut invoke = agent.invoke_request();
-                            invoke.get().set_coordinates(&method);
-                            invoke.get().set_input(input);
-                            let results = invoke.send().promise.await.unwrap();
-                            let response_payload =
-                                Vec::from(results.get().unwrap().get_result().unwrap());
-
-                            responder
-                                .send(RegistryChannelMessage {
-                                    iomod_coords: coords,
-                                    method_name: method,
-                                    payload_type: "IOMOD_RESPONSE",
-                                    payload: response_payload,
-                                    responder: None,
-                                })
-                                .await
-                                .unwrap();
-                        }
-                        None => panic!("no IOmod registered at {}", coords),
-                    }
+                    // Each call runs as its own task, so that one waiting on its IOmod doesn't hold up the rest
+                    tokio::task::spawn_local(dispatch_call(
+                        rx_modules.clone(),
+                        rx_registered.clone(),
+                        msg,
+                        *REGISTRATION_WAIT,
+                    ));
                 }
             });
 
@@ -155,9 +178,138 @@ pub fn spawn_registry(mut rx: RegistryRx) -> Result<(), RegistryError> {
     Ok(())
 }
 
+async fn dispatch_call(
+    modules: ModuleMap,
+    registered: Rc<Notify>,
+    msg: RegistryChannelMessage,
+    registration_wait: Duration,
+) {
+    let responder = msg.responder.unwrap();
+    let coords = msg.iomod_coords;
+    let method = msg.method_name;
+    let requirement = match msg.version.as_deref().map(VersionReq::parse).transpose() {
+        Ok(requirement) => requirement.unwrap_or(VersionReq::STAR),
+        Err(err) => {
+            let error = IomodError::new(
+                INVALID_COORDS,
+                &format!("invalid version requirement for {}: {}", coords, err),
+                false,
+            );
+            return respond(responder, coords, method, None, Err(error)).await;
+        }
+    };
+
+    let found = find_iomod(&modules, &registered, &coords, &requirement, registration_wait).await;
+    let version = found
+        .as_ref()
+        .and_then(|(version, _)| version.as_ref().map(Version::to_string));
+    let result = match found {
+        Some((_, agent)) => {
+            info!("invoking call @ {}@{}.{}", coords, version.as_deref().unwrap_or("*"), method);
+            let mut invoke = agent.invoke_request();
+            invoke.get().set_coordinates(&method);
+            invoke.get().set_input(msg.payload.as_slice());
+            if let Some(traceparent) = &msg.traceparent {
+                invoke.get().set_traceparent(traceparent);
+            }
+            // A call which fails in transit is reported like an error from the IOmod
+            invoke
+                .send()
+                .promise
+                .await
+                .and_then(|results| read_call_result(results.get()?))
+                .unwrap_or_else(|err| {
+                    error!("IOmod call @ {}.{} failed: {}", coords, method, err);
+                    Err(IomodError::new(UNAVAILABLE, &err.to_string(), true))
+                })
+        }
+        None => {
+            warn!("no IOmod registered at {} matching version {}", coords, requirement);
+            Err(IomodError::new(
+                COORDS_NOT_FOUND,
+                &format!("no IOmod registered at {} matching version {}", coords, requirement),
+                false,
+            ))
+        }
+    };
+    respond(responder, coords, method, version, result).await
+}
+
+async fn respond(
+    responder: RegistryTx,
+    coords: String,
+    method: String,
+    version: Option<String>,
+    result: CallResult,
+) {
+    let (payload, error) = match result {
+        Ok(payload) => (payload, None),
+        Err(error) => (Vec::new(), Some(error)),
+    };
+
+    responder
+        .send(RegistryChannelMessage {
+            iomod_coords: coords,
+            method_name: method,
+            payload_type: "IOMOD_RESPONSE",
+            payload,
+            version,
+            traceparent: None,
+            error,
+            responder: None,
+        })
+        .await
+        .unwrap_or_else(|_| {
+            // The caller has timed out or been cancelled
+            debug!("dropped response to an IOmod call which is no longer waiting")
+        });
+}
+
+/// Look up the latest version of the IOmod at `coords` which matches `requirement`,
+/// waiting up to `registration_wait` for one to register
+async fn find_iomod(
+    modules: &ModuleMap,
+    registered: &Notify,
+    coords: &str,
+    requirement: &VersionReq,
+    registration_wait: Duration,
+) -> Option<(Option<Version>, agent::Client)> {
+    let deadline = tokio::time::Instant::now() + registration_wait;
+    loop {
+        // Created before the lookup, so that a registration in between isn't missed
+        let notified = registered.notified();
+        let found = RefCell::borrow(modules)
+            .get(coords)
+            .and_then(|versions| select_version(versions, requirement))
+            .map(|(version, agent)| (version.clone(), agent.clone()));
+        if found.is_some() {
+            return found;
+        }
+        if tokio::time::timeout_at(deadline, notified).await.is_err() {
+            return None;
+        }
+    }
+}
+
+/// The latest of `versions` which matches `requirement`. An IOmod registered without a version
+/// could be any version, so it matches every requirement, but only if no versioned IOmod does.
+fn select_version<'a, T>(
+    versions: &'a BTreeMap<Option<Version>, T>,
+    requirement: &VersionReq,
+) -> Option<(&'a Option<Version>, &'a T)> {
+    // `None` sorts first, so it is the last to be considered
+    versions.iter().rev().find(|(version, _)| match version {
+        Some(version) => requirement.matches(version),
+        None => true,
+    })
+}
+
 impl Registry {
-    pub fn new(modules: ModuleMap) -> Self {
-        Self { modules }
+    pub fn new(modules: ModuleMap, registered: Rc<Notify>) -> Self {
+        Self {
+            modules,
+            registered,
+        }
     }
 }
 
@@ -168,6 +320,19 @@ impl registry::Server for Registry {
         mut _results: registry::RegisterResults,
     ) -> Promise<(), capnp::Error> {
         let coordinates: String = String::from(params.get().unwrap().get_coordinates().unwrap());
+        // IOmods built before versioned registration don't send a version
+        let version = match params.get().unwrap().get_version().unwrap() {
+            "" => None,
+            version => match Version::parse(version) {
+                Ok(version) => Some(version),
+                Err(err) => {
+                    return Promise::err(capnp::Error::failed(format!(
+                        "invalid version {} for IOmod at {}: {}",
+                        version, coordinates, err
+                    )))
+                }
+            },
+        };
         let module: Rc<RefCell<iomod::Client>> =
             Rc::new(RefCell::new(params.get().unwrap().get_iomod().unwrap()));
 
@@ -175,9 +340,104 @@ impl registry::Server for Registry {
 
         let modules = self.modules.clone();
         let mut modules_ref = RefCell::borrow_mut(&modules);
-        modules_ref.entry(coordinates.clone()).or_insert(agent);
-        info!("registered IOmod at coordinates {}", coordinates.clone());
+        // A version which registers again, e.g. after its process restarted, replaces the old registration
+        modules_ref
+            .entry(coordinates.clone())
+            .or_default()
+            .insert(version.clone(), agent);
+        REGISTRY_IOMODS.set(modules_ref.values().map(BTreeMap::len).sum::<usize>() as i64);
+        match &version {
+            Some(version) => info!("registered IOmod at coordinates {}@{}", coordinates, version),
+            None => info!("registered unversioned IOmod at coordinates {}", coordinates),
+        }
+        drop(modules_ref);
+        self.registered.notify_waiters();
 
         Promise::ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+    use std::time::{Duration, Instant};
+
+    use semver::{Version, VersionReq};
+    use tokio::sync::{mpsc, Notify};
+
+    use super::{dispatch_call, select_version, ModuleMap, RegistryChannelMessage, COORDS_NOT_FOUND};
+
+    fn select(versions: &[Option<&str>], requirement: &str) -> Option<Option<String>> {
+        let versions: BTreeMap<Option<Version>, ()> = versions
+            .iter()
+            .map(|version| (version.map(|v| Version::parse(v).unwrap()), ()))
+            .collect();
+        select_version(&versions, &VersionReq::parse(requirement).unwrap())
+            .map(|(version, _)| version.as_ref().map(Version::to_string))
+    }
+
+    #[test]
+    fn test_select_version() {
+        let versions = [Some("1.0.0"), Some("1.2.3"), Some("1.10.0"), Some("2.0.0")];
+        assert_eq!(Some(Some("1.10.0".to_string())), select(&versions, "^1"));
+        assert_eq!(Some(Some("1.2.3".to_string())), select(&versions, ">=1.2, <1.5"));
+        assert_eq!(Some(Some("1.0.0".to_string())), select(&versions, "=1.0.0"));
+        assert_eq!(Some(Some("2.0.0".to_string())), select(&versions, "*"));
+        assert_eq!(None, select(&versions, "^3"));
+        assert_eq!(None, select(&[], "*"));
+    }
+
+    #[test]
+    fn test_select_version_prerelease() {
+        // Pre-releases only match requirements which name them
+        let versions = [Some("1.0.0"), Some("1.1.0-alpha.1")];
+        assert_eq!(Some(Some("1.0.0".to_string())), select(&versions, "^1"));
+        assert_eq!(Some(Some("1.1.0-alpha.1".to_string())), select(&versions, "^1.1.0-alpha"));
+    }
+
+    #[test]
+    fn test_select_unversioned() {
+        // An IOmod which registered without a version matches any requirement
+        assert_eq!(Some(None), select(&[None], "^1"));
+        assert_eq!(Some(None), select(&[None], "*"));
+        // but a matching versioned IOmod is preferred
+        assert_eq!(Some(Some("1.2.0".to_string())), select(&[None, Some("1.2.0")], "^1"));
+        assert_eq!(Some(None), select(&[None, Some("2.0.0")], "^1"));
+    }
+
+    #[tokio::test]
+    async fn test_unknown_coords() {
+        let modules = ModuleMap::default();
+        let registered = Rc::new(Notify::new());
+        let (responder, mut response_rx) = mpsc::channel(1);
+        let msg = RegistryChannelMessage {
+            iomod_coords: "akkoro.std.missing".to_string(),
+            method_name: "call".to_string(),
+            payload_type: "IOMOD_REQUEST",
+            payload: Vec::new(),
+            version: None,
+            traceparent: None,
+            error: None,
+            responder: Some(responder),
+        };
+
+        let wait = Duration::from_millis(100);
+        let started = Instant::now();
+        let other_registration = async {
+            // Another IOmod registering doesn't end the wait
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            registered.notify_waiters();
+        };
+        tokio::time::timeout(Duration::from_secs(1), async {
+            tokio::join!(dispatch_call(modules, registered.clone(), msg, wait), other_registration)
+        })
+        .await
+        .expect("the call should fail once the wait is over");
+
+        assert!(started.elapsed() >= wait);
+        let response = response_rx.recv().await.unwrap();
+        assert!(response.payload.is_empty());
+        assert_eq!(COORDS_NOT_FOUND, response.error.unwrap().code);
+    }
+}
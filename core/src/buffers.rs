@@ -15,11 +15,27 @@ impl IoBuffer {
     }
 
     pub fn set(&mut self, ioid: usize, bytes: Vec<u8>) -> usize {
-        self.buffers.insert(ioid, bytes.clone());
-        bytes.len()
+        let len = bytes.len();
+        self.buffers.insert(ioid, bytes);
+        len
     }
 
-    pub fn get(&self, ioid: usize) -> Vec<u8> {
-        self.buffers.get(&ioid).unwrap().clone()
+    /// Remove the buffer for `ioid`, returning its contents
+    pub fn take(&mut self, ioid: usize) -> Option<Vec<u8>> {
+        self.buffers.remove(&ioid)
+    }
+
+    /// Copy up to `len` bytes of the buffer for `ioid` from `offset`. Reading at or past the end
+    /// of the buffer returns no bytes.
+    pub fn read(&self, ioid: usize, offset: usize, len: usize) -> Option<Vec<u8>> {
+        self.buffers.get(&ioid).map(|bytes| {
+            let start = offset.min(bytes.len());
+            let end = offset.saturating_add(len).min(bytes.len());
+            bytes[start..end].to_vec()
+        })
+    }
+
+    pub fn remove(&mut self, ioid: usize) {
+        self.buffers.remove(&ioid);
     }
 }
@@ -3,15 +3,38 @@ pub use wasmtime::AsContextMut;
 pub mod buffers;
 pub mod jwt;
 pub mod policy_manager;
+pub mod runtime_environment;
+pub mod telemetry;
 pub mod threader;
 pub mod wasm;
 
+#[async_trait::async_trait]
 pub trait RuntimeAbi<S>: SecretsAbi
 where
     S: Clone + Send + Sized + 'static,
 {
     fn success(status_tx: crate::wasm::StatusTx<S>, response: Vec<u8>, request_id: Option<String>);
     fn failure(status_tx: crate::wasm::StatusTx<S>, response: Vec<u8>, request_id: Option<String>);
+    fn respond(
+        status_tx: crate::wasm::StatusTx<S>,
+        response: crate::wasm::asml_rt::HttpResponse,
+        request_id: Option<String>,
+    );
+
+    /// True if the runtime sends streamed responses as they are written. Otherwise, the stream
+    /// is buffered and passed to `respond` once it is finished.
+    /// The guest's host call awaits each part of a streamed response, so a runtime can hold the
+    /// guest back until there is room for it, without blocking the thread running the guest.
+    const STREAMING: bool = false;
+    async fn begin_response(
+        _status_tx: crate::wasm::StatusTx<S>,
+        _status: u16,
+        _headers: Vec<(String, String)>,
+        _request_id: Option<String>,
+    ) {
+    }
+    async fn write_chunk(_status_tx: crate::wasm::StatusTx<S>, _chunk: Vec<u8>, _request_id: Option<String>) {}
+    async fn finish_response(_status_tx: crate::wasm::StatusTx<S>, _request_id: Option<String>) {}
 }
 
 pub trait SecretsAbi: KeysAbi {
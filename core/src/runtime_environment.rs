@@ -0,0 +1,141 @@
+//! Descriptors of the environments functions run in.
+//!
+//! Natively compiled functions (e.g. Rust) are run directly. Interpreted languages run an
+//! interpreter component, which is given the function's source and the interpreter's own files
+//! through bind paths. Supporting a new language means adding a descriptor to `RUNTIME_ENVIRONMENTS`.
+
+use std::path::Path;
+
+pub struct RuntimeEnvironment {
+    /// Identifies the environment in `ASML_FUNCTION_ENV`
+    pub name: &'static str,
+    /// Function languages which run in this environment
+    pub languages: &'static [&'static str],
+    /// Arguments passed to the guest
+    pub args: &'static [&'static str],
+    /// Environment variables set for the guest, in addition to the function's own
+    pub env: &'static [(&'static str, &'static str)],
+    /// Directories bound (read-only) into the guest
+    pub bind_paths: &'static [BindPath],
+    pub interpreter: Option<Interpreter>,
+}
+
+pub struct Interpreter {
+    pub version: &'static str,
+    /// Name of the directory holding the interpreter's files, also the name of its archive
+    pub assets: &'static str,
+    /// Name of the directory holding the function's source, alongside the function component
+    pub source_dir: &'static str,
+}
+
+pub struct BindPath {
+    pub source: BindSource,
+    pub guest_path: &'static str,
+}
+
+pub enum BindSource {
+    /// The function's source directory
+    FunctionSource,
+    /// A directory inside the interpreter's assets
+    Assets(&'static str),
+}
+
+pub static NATIVE: RuntimeEnvironment = RuntimeEnvironment {
+    name: "native",
+    languages: &["rust"],
+    args: &[],
+    env: &[],
+    bind_paths: &[],
+    interpreter: None,
+};
+
+pub static RUBY: RuntimeEnvironment = RuntimeEnvironment {
+    name: "ruby",
+    languages: &["ruby"],
+    args: &["ruby", "/src/handler.rb"],
+    env: &[("RUBY_PLATFORM", "wasm32-wasi")],
+    bind_paths: &[
+        BindPath {
+            source: BindSource::FunctionSource,
+            guest_path: "/src",
+        },
+        BindPath {
+            source: BindSource::Assets("usr"),
+            guest_path: "/usr",
+        },
+    ],
+    interpreter: Some(Interpreter {
+        version: "3.3.0-dev",
+        assets: "ruby-wasm32-wasi",
+        source_dir: "rubysrc",
+    }),
+};
+
+pub static RUNTIME_ENVIRONMENTS: &[&RuntimeEnvironment] = &[&NATIVE, &RUBY];
+
+pub fn by_name(name: &str) -> Option<&'static RuntimeEnvironment> {
+    RUNTIME_ENVIRONMENTS.iter().find(|e| e.name == name).copied()
+}
+
+pub fn by_language(language: &str) -> Option<&'static RuntimeEnvironment> {
+    RUNTIME_ENVIRONMENTS
+        .iter()
+        .find(|e| e.languages.contains(&language))
+        .copied()
+}
+
+impl RuntimeEnvironment {
+    /// Resolve the environment's bind paths to (host path, guest path) pairs, given where the host
+    /// keeps the function's source and the interpreter's assets
+    pub fn resolve_bind_paths(&self, source_dir: &Path, assets_dir: &Path) -> Vec<(String, String)> {
+        self.bind_paths
+            .iter()
+            .map(|bind| {
+                let host_path = match bind.source {
+                    BindSource::FunctionSource => source_dir.to_path_buf(),
+                    BindSource::Assets(dir) => assets_dir.join(dir),
+                };
+                (host_path.to_string_lossy().to_string(), bind.guest_path.to_string())
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::runtime_environment::{by_language, by_name, RUNTIME_ENVIRONMENTS};
+
+    #[test]
+    fn test_by_language() {
+        assert_eq!("native", by_language("rust").unwrap().name);
+        assert_eq!("ruby", by_language("ruby").unwrap().name);
+        assert!(by_language("cobol").is_none());
+    }
+
+    #[test]
+    fn test_by_name() {
+        for environment in RUNTIME_ENVIRONMENTS {
+            assert_eq!(environment.name, by_name(environment.name).unwrap().name);
+        }
+        assert!(by_name("rust").is_none());
+    }
+
+    #[test]
+    fn test_resolve_bind_paths() {
+        let ruby = by_name("ruby").unwrap();
+        let paths = ruby.resolve_bind_paths(Path::new("/fn/rubysrc"), Path::new("/assets/ruby-wasm32-wasi"));
+        assert_eq!(
+            vec![
+                ("/fn/rubysrc".to_string(), "/src".to_string()),
+                ("/assets/ruby-wasm32-wasi/usr".to_string(), "/usr".to_string()),
+            ],
+            paths
+        );
+        assert!(by_name("native")
+            .unwrap()
+            .resolve_bind_paths(Path::new("/fn"), Path::new("/assets"))
+            .is_empty());
+    }
+}
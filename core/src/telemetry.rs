@@ -0,0 +1,85 @@
+//! OpenTelemetry tracing for invocations & IOmod calls.
+//!
+//! Spans are exported over OTLP (gRPC) when `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Trace context
+//! is carried between the runtime's threads, and into IOmod processes, as a W3C `traceparent`.
+
+use std::collections::HashMap;
+
+use once_cell::sync::{Lazy, OnceCell};
+use opentelemetry::propagation::TextMapPropagator;
+use opentelemetry::trace::TraceContextExt;
+use opentelemetry::KeyValue;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Tracer, TracerProvider};
+use opentelemetry_sdk::Resource;
+use tracing::{Span, Subscriber};
+use tracing_opentelemetry::{OpenTelemetryLayer, OpenTelemetrySpanExt};
+use tracing_subscriber::registry::LookupSpan;
+
+/// Collector receiving spans, e.g. `http://localhost:4317`; tracing is disabled if unset
+pub static OTLP_ENDPOINT: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok().filter(|e| !e.is_empty()));
+
+static TRACER_PROVIDER: OnceCell<TracerProvider> = OnceCell::new();
+
+/// A `tracing` layer exporting spans to `OTLP_ENDPOINT`, if one is set.
+/// Must be called from within a Tokio runtime, on which spans are exported in batches.
+pub fn otlp_layer<S>(service_name: &str) -> anyhow::Result<Option<OpenTelemetryLayer<S, Tracer>>>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let endpoint = match OTLP_ENDPOINT.as_ref() {
+        Some(endpoint) => endpoint,
+        None => return Ok(None),
+    };
+    let tracer = opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_endpoint(endpoint))
+        .with_trace_config(opentelemetry_sdk::trace::config().with_resource(Resource::new(vec![
+            KeyValue::new("service.name", service_name.to_string()),
+        ])))
+        .install_batch(opentelemetry_sdk::runtime::Tokio)?;
+    if let Some(provider) = tracer.provider() {
+        let _ = TRACER_PROVIDER.set(provider);
+    }
+    Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+}
+
+/// Export any spans which are waiting for the next batch. Blocks until they are sent.
+pub fn flush() {
+    if let Some(provider) = TRACER_PROVIDER.get() {
+        for result in provider.force_flush() {
+            if let Err(err) = result {
+                tracing::warn!("could not export spans: {}", err.to_string());
+            }
+        }
+    }
+}
+
+/// The context described by a W3C `traceparent`, to be set as the parent of a span.
+/// Without a valid `traceparent`, the span starts a new trace.
+pub fn context_from(traceparent: Option<&str>) -> opentelemetry::Context {
+    let mut carrier = HashMap::new();
+    if let Some(traceparent) = traceparent {
+        carrier.insert("traceparent".to_string(), traceparent.to_string());
+    }
+    TraceContextPropagator::new().extract(&carrier)
+}
+
+/// The W3C `traceparent` identifying `span`, if it is being traced
+pub fn traceparent(span: &Span) -> Option<String> {
+    let mut carrier: HashMap<String, String> = HashMap::new();
+    TraceContextPropagator::new().inject_context(&span.context(), &mut carrier);
+    carrier.remove("traceparent")
+}
+
+/// The id of the trace `span` belongs to, if it is being traced
+pub fn trace_id(span: &Span) -> Option<String> {
+    let context = span.context();
+    let span_context = context.span().span_context().clone();
+    match span_context.is_valid() {
+        true => Some(span_context.trace_id().to_string()),
+        false => None,
+    }
+}
@@ -4,19 +4,63 @@
 
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc;
+use once_cell::sync::Lazy;
+use prometheus::{register_histogram_vec, register_int_counter_vec, HistogramVec, IntCounterVec};
+use tokio::sync::{mpsc, watch};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use wasmtime_wasi::preview2::Subscribe;
 
-use assemblylift_core_iomod::registry::{RegistryChannelMessage, RegistryTx};
+use assemblylift_core_iomod::registry::{
+    RegistryChannelMessage, RegistryTx, COORDS_NOT_FOUND, INVALID_COORDS, UNAVAILABLE,
+};
 
 use super::buffers::IoBuffer;
+use super::telemetry;
 use super::wasm::asml_io;
 
 pub type IoId = u32;
 
+/// Semver requirements of a function's IOmod dependencies, by IOmod coordinates
+pub type IomodVersions = HashMap<String, String>;
+
+/// Read a function's IOmod dependencies from a JSON object of coordinates to version requirements,
+/// as set in `ASML_FUNCTION_IOMODS`
+pub fn iomod_versions_from_json(json: &str) -> anyhow::Result<IomodVersions> {
+    Ok(serde_json::from_str(json)?)
+}
+
+/// Time an IOmod call may take to respond, unless the function or call sets its own timeout
+pub const DEFAULT_CALL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// IOmod calls made by guests, by IOmod coordinates, method & outcome
+pub static IOMOD_CALLS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asml_iomod_calls_total",
+        "IOmod calls made by guests",
+        &["iomod", "method", "outcome"]
+    )
+    .unwrap()
+});
+
+/// Time from invoking an IOmod call until its response arrives
+pub static IOMOD_CALL_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "asml_iomod_call_duration_seconds",
+        "Latency of IOmod calls made by guests",
+        &["iomod", "method"]
+    )
+    .unwrap()
+});
+
 pub struct Threader<S> {
     io_memory: Arc<Mutex<IoMemory>>,
     registry_tx: RegistryTx,
+    default_timeout: Duration,
+    iomod_versions: IomodVersions,
+    calls: HashMap<IoId, JoinHandle<()>>,
     _phantom: std::marker::PhantomData<S>,
 }
 
@@ -24,11 +68,20 @@ impl<S> Threader<S>
 where
     S: Clone + Send + Sized + 'static,
 {
-    /// Create a new Threader instance with the provided sender `tx`
-    pub fn new(tx: RegistryTx) -> Self {
+    /// Create a new Threader instance with the provided sender `tx`. Calls which don't respond
+    /// within `default_timeout` (or `DEFAULT_CALL_TIMEOUT`) time out, unless invoked with their own timeout.
+    /// Calls are routed to the version of their IOmod matching `iomod_versions`, or else the latest version.
+    pub fn new(
+        tx: RegistryTx,
+        default_timeout: Option<Duration>,
+        iomod_versions: IomodVersions,
+    ) -> Self {
         Threader {
             io_memory: Arc::new(Mutex::new(IoMemory::new())),
             registry_tx: tx,
+            default_timeout: default_timeout.unwrap_or(DEFAULT_CALL_TIMEOUT),
+            iomod_versions,
+            calls: Default::default(),
             _phantom: std::marker::PhantomData::default(),
         }
     }
@@ -41,24 +94,71 @@ where
         }
     }
 
-    /// Poll the runtime for the completion status of call associated with `ioid`
-    pub fn poll(&mut self, ioid: IoId) -> Option<Vec<u8>> {
+    /// Poll the runtime for the completion status of call associated with `ioid`.
+    /// A completed call's response is taken, and the call released; a timed-out call is released once reported.
+    pub fn poll(&mut self, ioid: IoId) -> Result<Vec<u8>, asml_io::PollError> {
+        match self.io_memory.clone().lock() {
+            Ok(mut memory) => match memory.poll(ioid) {
+                Some(IoStatus::Ready) => memory.take(ioid).ok_or(asml_io::PollError::InvalidIoid),
+                Some(IoStatus::Pending) => Err(asml_io::PollError::NotReady),
+                Some(IoStatus::TimedOut) => {
+                    memory.release(ioid);
+                    Err(asml_io::PollError::TimedOut)
+                }
+                Some(IoStatus::Failed(err)) => {
+                    memory.release(ioid);
+                    Err(asml_io::PollError::Failed(err))
+                }
+                None => Err(asml_io::PollError::InvalidIoid),
+            },
+            Err(_) => Err(asml_io::PollError::NotReady),
+        }
+    }
+
+    /// Read up to `len` bytes of the response to the call associated with `ioid`, from `offset`.
+    /// The response is kept until the call is released.
+    pub fn read(&mut self, ioid: IoId, offset: usize, len: usize) -> Result<Vec<u8>, asml_io::PollError> {
         match self.io_memory.clone().lock() {
             Ok(memory) => match memory.poll(ioid) {
-                true => Some(memory.buffer.get(ioid as usize)),
-                false => None,
+                Some(IoStatus::Ready) => memory
+                    .buffer
+                    .read(ioid as usize, offset, len)
+                    .ok_or(asml_io::PollError::InvalidIoid),
+                Some(IoStatus::Pending) => Err(asml_io::PollError::NotReady),
+                Some(IoStatus::TimedOut) => Err(asml_io::PollError::TimedOut),
+                Some(IoStatus::Failed(err)) => Err(asml_io::PollError::Failed(err)),
+                None => Err(asml_io::PollError::InvalidIoid),
             },
-            Err(_) => None,
+            Err(_) => Err(asml_io::PollError::NotReady),
+        }
+    }
+
+    /// A notifier which is ready once the call associated with `ioid` is no longer pending
+    pub fn subscribe(&mut self, ioid: IoId) -> IoReady {
+        IoReady {
+            ready: self.io_memory.lock().unwrap().subscribe(ioid),
+        }
+    }
+
+    /// Free the call associated with `ioid` and its response, aborting the call if it is still in flight
+    pub fn release(&mut self, ioid: IoId) {
+        if let Some(call) = self.calls.remove(&ioid) {
+            call.abort();
+        }
+        if let Ok(mut memory) = self.io_memory.clone().lock() {
+            memory.release(ioid);
         }
     }
 
     /// Invoke the IOmod call at `method_path` with `method_input`, and assign it id `ioid`.
-    /// A task is spawned on the tokio runtime which runs until the IOmod call responds.
+    /// A task is spawned on the tokio runtime which runs until the IOmod call responds, or until
+    /// `timeout` (or the Threader's default) passes.
     pub fn invoke(
         &mut self,
         method_path: &str,
         method_input: Vec<u8>,
         ioid: IoId,
+        timeout: Option<Duration>,
     ) -> Result<(), asml_io::IoError> {
         let io_memory = self.io_memory.clone();
 
@@ -71,39 +171,147 @@ where
         let iomod_coords = format!("{}.{}.{}", coords[0], coords[1], coords[2]);
         let method_name = format!("{}", coords[3]);
 
+        let version = self.iomod_versions.get(&iomod_coords).cloned();
         let registry_tx = self.registry_tx.clone();
         let (local_tx, mut local_rx) = mpsc::channel(100);
+        let labels = [iomod_coords.clone(), method_name.clone()];
+        let timeout = timeout.unwrap_or(self.default_timeout);
+        let started = Instant::now();
+        // A child of the calling invocation's span, which ends when the IOmod responds
+        let span = tracing::info_span!("iomod_call", iomod = %iomod_coords, method = %method_name);
+        let traceparent = telemetry::traceparent(&span);
 
-        tokio::spawn(async move {
-            registry_tx
-                .send(RegistryChannelMessage {
+        let call = tokio::spawn(
+            async move {
+                let request = RegistryChannelMessage {
                     iomod_coords,
                     method_name,
                     payload_type: "IOMOD_REQUEST",
                     payload: method_input,
-                    responder: Some(local_tx.clone()),
+                    version,
+                    traceparent,
+                    error: None,
+                    responder: Some(local_tx),
+                };
+                // Nothing would ever respond to the call, so it fails now rather than timing out
+                if let Err(err) = registry_tx.send(request).await {
+                    tracing::error!("could not send IOmod call to registry: {}", err.to_string());
+                    let err = asml_io::IoError::Iomod(asml_io::IomodError {
+                        code: UNAVAILABLE.to_string(),
+                        message: "the IOmod registry is not running".to_string(),
+                        retryable: true,
+                    });
+                    io_memory.lock().unwrap().handle_error(err, ioid);
+                    IOMOD_CALLS
+                        .with_label_values(&[&labels[0], &labels[1], "error"])
+                        .inc();
+                    return;
+                }
+
+                // A call whose responder is dropped without a response times out like any other
+                let response = tokio::time::timeout(timeout, async {
+                    match local_rx.recv().await {
+                        Some(response) => response,
+                        None => std::future::pending().await,
+                    }
                 })
-                .await
-                .unwrap();
-        });
-
-        tokio::spawn(async move {
-            if let Some(response) = local_rx.recv().await {
-                io_memory
-                    .lock()
-                    .unwrap()
-                    .handle_response(response.payload, ioid);
+                .await;
+                let outcome = match response {
+                    Ok(response) => match response.error {
+                        None => {
+                            io_memory
+                                .lock()
+                                .unwrap()
+                                .handle_response(response.payload, ioid);
+                            "ok"
+                        }
+                        Some(err) => {
+                            tracing::warn!("IOmod call {} failed: {}", ioid, err);
+                            let err = match err.code.as_str() {
+                                COORDS_NOT_FOUND => asml_io::IoError::CoordsNotFound,
+                                INVALID_COORDS => asml_io::IoError::InvalidCoords,
+                                _ => asml_io::IoError::Iomod(asml_io::IomodError {
+                                    code: err.code,
+                                    message: err.message,
+                                    retryable: err.retryable,
+                                }),
+                            };
+                            io_memory.lock().unwrap().handle_error(err, ioid);
+                            "error"
+                        }
+                    },
+                    Err(_) => {
+                        tracing::warn!("IOmod call {} timed out after {:?}", ioid, timeout);
+                        io_memory.lock().unwrap().handle_timeout(ioid);
+                        "timed_out"
+                    }
+                };
+                IOMOD_CALLS
+                    .with_label_values(&[&labels[0], &labels[1], outcome])
+                    .inc();
+                IOMOD_CALL_SECONDS
+                    .with_label_values(&[&labels[0], &labels[1]])
+                    .observe(started.elapsed().as_secs_f64());
             }
-        });
+            .instrument(span),
+        );
+
+        self.calls.retain(|_, call| !call.is_finished());
+        self.calls.insert(ioid, call);
 
         Ok(())
     }
+
+    /// Abort every call which is still waiting for its IOmod to respond, and release every call,
+    /// whether complete or not; none of them will be polled again
+    pub fn cancel_all(&mut self) {
+        for (ioid, call) in self.calls.drain() {
+            if !call.is_finished() {
+                tracing::debug!("cancelling IOmod call {}", ioid);
+                call.abort();
+            }
+        }
+        self.io_memory.lock().unwrap().release_all();
+    }
+}
+
+// The Threader is owned by the function's store, so in-flight calls end with the store
+impl<S> Drop for Threader<S> {
+    fn drop(&mut self) {
+        for (_, call) in self.calls.drain() {
+            call.abort();
+        }
+    }
+}
+
+/// Backs the `pollable` a guest subscribes to for an IOmod call, so that the guest can block
+/// on `wasi:io/poll` until the call completes instead of polling it repeatedly.
+pub struct IoReady {
+    ready: watch::Receiver<bool>,
+}
+
+#[async_trait::async_trait]
+impl Subscribe for IoReady {
+    async fn ready(&mut self) {
+        // The sender is dropped when the call completes, which also ends the wait
+        let _ = self.ready.wait_for(|ready| *ready).await;
+    }
+}
+
+#[derive(Clone)]
+enum IoStatus {
+    Pending,
+    Ready,
+    TimedOut,
+    /// The IOmod responded with an error
+    Failed(asml_io::IoError),
 }
 
 struct IoMemory {
     next_id: IoId,
     buffer: IoBuffer,
-    io_status: HashMap<IoId, bool>,
+    io_status: HashMap<IoId, IoStatus>,
+    waiters: HashMap<IoId, watch::Sender<bool>>,
 }
 
 impl IoMemory {
@@ -112,25 +320,280 @@ impl IoMemory {
             next_id: 1, // id 0 is reserved (null)
             buffer: IoBuffer::new(),
             io_status: Default::default(),
+            waiters: Default::default(),
         }
     }
 
     fn next_id(&mut self) -> Option<IoId> {
         let next_id = self.next_id.clone();
         self.next_id += 1;
-        self.io_status.insert(next_id, false);
+        self.io_status.insert(next_id, IoStatus::Pending);
         Some(next_id)
     }
 
-    fn poll(&self, ioid: IoId) -> bool {
+    fn poll(&self, ioid: IoId) -> Option<IoStatus> {
+        self.io_status.get(&ioid).cloned()
+    }
+
+    fn subscribe(&mut self, ioid: IoId) -> watch::Receiver<bool> {
         match self.io_status.get(&ioid) {
-            Some(status) => *status,
-            None => false,
+            Some(IoStatus::Pending) => self
+                .waiters
+                .entry(ioid)
+                .or_insert_with(|| watch::channel(false).0)
+                .subscribe(),
+            // Polling a call which is complete, or unknown, returns immediately
+            _ => watch::channel(true).1,
         }
     }
 
+    fn notify(&mut self, ioid: IoId) {
+        if let Some(waiter) = self.waiters.remove(&ioid) {
+            waiter.send_replace(true);
+        }
+    }
+
+    fn take(&mut self, ioid: IoId) -> Option<Vec<u8>> {
+        self.io_status.remove(&ioid);
+        self.buffer.take(ioid as usize)
+    }
+
+    fn release(&mut self, ioid: IoId) {
+        self.io_status.remove(&ioid);
+        self.buffer.remove(ioid as usize);
+        self.notify(ioid);
+    }
+
+    fn release_all(&mut self) {
+        let ioids: Vec<IoId> = self.io_status.keys().copied().collect();
+        for ioid in ioids {
+            self.release(ioid);
+        }
+    }
+
+    // Calls which were released while in flight are no longer tracked, and their responses are dropped
     fn handle_response(&mut self, response: Vec<u8>, ioid: IoId) {
-        self.buffer.set(ioid as usize, response.clone());
-        self.io_status.insert(ioid, true);
+        if let Some(status) = self.io_status.get_mut(&ioid) {
+            self.buffer.set(ioid as usize, response);
+            *status = IoStatus::Ready;
+        }
+        self.notify(ioid);
+    }
+
+    fn handle_timeout(&mut self, ioid: IoId) {
+        if let Some(status) = self.io_status.get_mut(&ioid) {
+            *status = IoStatus::TimedOut;
+        }
+        self.notify(ioid);
+    }
+
+    fn handle_error(&mut self, err: asml_io::IoError, ioid: IoId) {
+        if let Some(status) = self.io_status.get_mut(&ioid) {
+            *status = IoStatus::Failed(err);
+        }
+        self.notify(ioid);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+    use opentelemetry_sdk::trace::TracerProvider;
+    use tokio::sync::mpsc;
+    use tracing_opentelemetry::OpenTelemetrySpanExt;
+    use tracing_subscriber::layer::SubscriberExt;
+    use wasmtime_wasi::preview2::Subscribe;
+
+    use assemblylift_core_iomod::registry::{RegistryChannelMessage, UNAVAILABLE};
+
+    use super::Threader;
+    use crate::telemetry;
+    use crate::wasm::asml_io;
+
+    const TRACE_ID: &str = "0af7651916cd43dd8448eb211c80319c";
+
+    /// Stands in for an OTLP collector, keeping the spans exported to it
+    #[derive(Clone, Debug, Default)]
+    struct Collector {
+        spans: Arc<Mutex<Vec<SpanData>>>,
+    }
+
+    impl SpanExporter for Collector {
+        fn export(
+            &mut self,
+            batch: Vec<SpanData>,
+        ) -> Pin<Box<dyn Future<Output = ExportResult> + Send>> {
+            self.spans.lock().unwrap().extend(batch);
+            Box::pin(std::future::ready(Ok(())))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_iomod_call_traceparent() {
+        let collector = Collector::default();
+        let provider = TracerProvider::builder()
+            .with_simple_exporter(collector.clone())
+            .build();
+        let subscriber = tracing_subscriber::registry()
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+        let _subscriber = tracing::subscriber::set_default(subscriber);
+
+        let (registry_tx, mut registry_rx) = mpsc::channel(8);
+        let mut threader = Threader::<()>::new(registry_tx, None, Default::default());
+        let ioid = threader.next_ioid().unwrap();
+
+        // The invocation continues a trace started by its caller
+        let caller = format!("00-{}-b7ad6b7169203331-01", TRACE_ID);
+        let invocation = tracing::info_span!("invocation");
+        invocation.set_parent(telemetry::context_from(Some(&caller)));
+        invocation
+            .in_scope(|| threader.invoke("akkoro.std.http.request", Vec::new(), ioid, None))
+            .unwrap();
+
+        let call = registry_rx.recv().await.unwrap();
+        let traceparent = call.traceparent.clone().expect("the call should carry a traceparent");
+        call.responder
+            .unwrap()
+            .send(RegistryChannelMessage {
+                iomod_coords: call.iomod_coords,
+                method_name: call.method_name,
+                payload_type: "IOMOD_RESPONSE",
+                payload: b"{}".to_vec(),
+                version: None,
+                traceparent: None,
+                error: None,
+                responder: None,
+            })
+            .await
+            .unwrap();
+        // The call's span ends with its task
+        threader.calls.remove(&ioid).unwrap().await.unwrap();
+        for result in provider.force_flush() {
+            result.unwrap();
+        }
+
+        let parts: Vec<&str> = traceparent.split('-').collect();
+        assert_eq!(4, parts.len());
+        assert_eq!(TRACE_ID, parts[1]);
+
+        let spans = collector.spans.lock().unwrap();
+        let span = spans
+            .iter()
+            .find(|span| span.name == "iomod_call")
+            .expect("the call's span should be exported");
+        assert_eq!(parts[1], span.span_context.trace_id().to_string());
+        assert_eq!(parts[2], span.span_context.span_id().to_string());
+        assert!(matches!(threader.poll(ioid), Ok(response) if response == b"{}"));
+    }
+
+    #[tokio::test]
+    async fn test_io_ready() {
+        let (registry_tx, mut registry_rx) = mpsc::channel(8);
+        let mut threader = Threader::<()>::new(registry_tx, None, Default::default());
+        let ioid = threader.next_ioid().unwrap();
+        threader
+            .invoke("akkoro.std.http.request", Vec::new(), ioid, None)
+            .unwrap();
+        let call = registry_rx.recv().await.unwrap();
+
+        // The guest waits while the call is pending...
+        let mut waiter = threader.subscribe(ioid);
+        assert!(tokio::time::timeout(Duration::from_millis(50), waiter.ready())
+            .await
+            .is_err());
+        assert!(matches!(threader.poll(ioid), Err(asml_io::PollError::NotReady)));
+
+        // ...and is woken once the response arrives
+        call.responder
+            .unwrap()
+            .send(RegistryChannelMessage {
+                iomod_coords: call.iomod_coords,
+                method_name: call.method_name,
+                payload_type: "IOMOD_RESPONSE",
+                payload: b"{}".to_vec(),
+                version: None,
+                traceparent: None,
+                error: None,
+                responder: None,
+            })
+            .await
+            .unwrap();
+        tokio::time::timeout(Duration::from_secs(1), waiter.ready())
+            .await
+            .expect("the waiter should be woken by the response");
+        assert!(matches!(threader.poll(ioid), Ok(response) if response == b"{}"));
+    }
+
+    #[tokio::test]
+    async fn test_registry_closed() {
+        let (registry_tx, registry_rx) = mpsc::channel(8);
+        drop(registry_rx);
+        let mut threader = Threader::<()>::new(registry_tx, None, Default::default());
+        let ioid = threader.next_ioid().unwrap();
+        threader
+            .invoke("akkoro.std.http.request", Vec::new(), ioid, None)
+            .unwrap();
+
+        // The call fails as soon as it can't reach the registry, without waiting out its timeout
+        tokio::time::timeout(Duration::from_secs(1), threader.calls.remove(&ioid).unwrap())
+            .await
+            .expect("the call should fail immediately")
+            .unwrap();
+        assert!(matches!(
+            threader.poll(ioid),
+            Err(asml_io::PollError::Failed(asml_io::IoError::Iomod(err)))
+                if err.code == UNAVAILABLE && err.retryable
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_cancel_all() {
+        let (registry_tx, mut registry_rx) = mpsc::channel(8);
+        let mut threader = Threader::<()>::new(registry_tx, None, Default::default());
+        let pending = threader.next_ioid().unwrap();
+        threader
+            .invoke("akkoro.std.http.request", Vec::new(), pending, None)
+            .unwrap();
+        let complete = threader.next_ioid().unwrap();
+        threader
+            .invoke("akkoro.std.http.request", Vec::new(), complete, None)
+            .unwrap();
+
+        // Only the second call gets a response, which is never taken
+        let _pending_call = registry_rx.recv().await.unwrap();
+        let complete_call = registry_rx.recv().await.unwrap();
+        complete_call
+            .responder
+            .unwrap()
+            .send(RegistryChannelMessage {
+                iomod_coords: complete_call.iomod_coords,
+                method_name: complete_call.method_name,
+                payload_type: "IOMOD_RESPONSE",
+                payload: b"{}".to_vec(),
+                version: None,
+                traceparent: None,
+                error: None,
+                responder: None,
+            })
+            .await
+            .unwrap();
+        threader.calls.remove(&complete).unwrap().await.unwrap();
+        let mut waiter = threader.subscribe(pending);
+
+        threader.cancel_all();
+        assert!(threader.calls.is_empty());
+        assert!(threader.io_memory.lock().unwrap().io_status.is_empty());
+        assert!(matches!(threader.poll(pending), Err(asml_io::PollError::InvalidIoid)));
+        assert!(matches!(threader.poll(complete), Err(asml_io::PollError::InvalidIoid)));
+        // A guest waiting on a cancelled call is woken
+        tokio::time::timeout(Duration::from_secs(1), waiter.ready())
+            .await
+            .expect("the waiter should be woken");
     }
 }
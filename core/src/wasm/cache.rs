@@ -1,41 +1,297 @@
 use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
+use once_cell::sync::Lazy;
 use serde::{Serialize, de::DeserializeOwned};
 use tracing::debug;
 
+/// Maximum number of objects held by a function's cache
+pub static CACHE_MAX_ENTRIES: Lazy<usize> = Lazy::new(|| {
+    std::env::var("ASML_CACHE_MAX_ENTRIES")
+        .ok()
+        .and_then(|n| n.parse::<usize>().ok())
+        .unwrap_or(256)
+});
+
+/// Maximum total size of the (encoded) objects held by a function's cache
+pub static CACHE_MAX_MB: Lazy<usize> = Lazy::new(|| {
+    std::env::var("ASML_CACHE_MAX_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<usize>().ok())
+        .unwrap_or(16)
+});
+
+/// Lifetime of an object stored without its own TTL
+pub static CACHE_DEFAULT_TTL: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("ASML_CACHE_TTL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .unwrap_or(3600),
+    )
+});
+
+/// Counters describing how a cache is being used
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub expirations: u64,
+    pub evictions: u64,
+    pub entries: usize,
+    pub bytes: usize,
+}
+
+impl CacheStats {
+    /// Fraction of lookups which found a live object, or 0 before any lookup
+    pub fn hit_rate(&self) -> f64 {
+        match self.hits + self.misses {
+            0 => 0f64,
+            total => self.hits as f64 / total as f64,
+        }
+    }
+}
+
+struct Entry {
+    data: Vec<u8>,
+    expires_at: Instant,
+    last_used: u64,
+}
+
+/// An in-memory object cache, bounded in both entries & bytes. Keys are namespaced, e.g. by the
+/// host interface storing the object. Objects expire after their TTL, and the least recently used
+/// objects are evicted when the cache is full.
 pub struct Cache {
-    data: BTreeMap<String, Vec<u8>>,
+    data: BTreeMap<(String, String), Entry>,
+    max_entries: usize,
+    max_bytes: usize,
+    bytes: usize,
+    clock: u64,
+    stats: CacheStats,
 }
 
 impl Cache {
     pub fn new() -> Self {
+        Self::with_limits(*CACHE_MAX_ENTRIES, *CACHE_MAX_MB * 1024 * 1024)
+    }
+
+    pub fn with_limits(max_entries: usize, max_bytes: usize) -> Self {
         Self {
             data: Default::default(),
+            max_entries,
+            max_bytes,
+            bytes: 0,
+            clock: 0,
+            stats: Default::default(),
         }
     }
 
-    pub fn put<T: Serialize>(&mut self, key: &str, object: &T) -> anyhow::Result<()> {
-        debug!("storing cache object @ {}", key);
+    /// Store `object` under `namespace`/`key`, replacing any existing object. The object expires after
+    /// `ttl`, or `CACHE_DEFAULT_TTL` if none is given.
+    pub fn put<T: Serialize>(
+        &mut self,
+        namespace: &str,
+        key: &str,
+        object: &T,
+        ttl: Option<Duration>,
+    ) -> anyhow::Result<()> {
+        debug!("storing cache object @ {}/{}", namespace, key);
         let encoded: Vec<u8> = bincode::serialize(object)
             .map_err(|e| anyhow!(e.to_string()))?;
-        self.data.insert(key.to_string(), encoded);
+        if encoded.len() > self.max_bytes || self.max_entries == 0 {
+            debug!("cache object @ {}/{} is too large to store", namespace, key);
+            return Ok(());
+        }
+
+        self.remove(namespace, key);
+        self.evict_expired();
+        while self.data.len() >= self.max_entries || self.bytes + encoded.len() > self.max_bytes {
+            self.evict_lru();
+        }
+
+        self.clock += 1;
+        self.bytes += encoded.len();
+        self.data.insert(
+            (namespace.to_string(), key.to_string()),
+            Entry {
+                data: encoded,
+                expires_at: Instant::now() + ttl.unwrap_or(*CACHE_DEFAULT_TTL),
+                last_used: self.clock,
+            },
+        );
         Ok(())
     }
 
-    pub fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
-        let encoded = match self.data.get(key) {
-            Some(e) => {
-                debug!("cache HIT getting object @ {}", key);
-                e
-            },
+    pub fn get<T: DeserializeOwned>(&mut self, namespace: &str, key: &str) -> anyhow::Result<Option<T>> {
+        let k = (namespace.to_string(), key.to_string());
+        let expired = match self.data.get(&k) {
+            Some(entry) => entry.expires_at <= Instant::now(),
             None => {
-                debug!("cache MISS getting object @ {}", key);
-                return Ok(None)
-            },
+                debug!("cache MISS getting object @ {}/{}", namespace, key);
+                self.stats.misses += 1;
+                return Ok(None);
+            }
         };
-        let decoded: T = bincode::deserialize(&encoded)
+        if expired {
+            debug!("cache EXPIRED getting object @ {}/{}", namespace, key);
+            self.remove(namespace, key);
+            self.stats.expirations += 1;
+            self.stats.misses += 1;
+            return Ok(None);
+        }
+
+        debug!("cache HIT getting object @ {}/{}", namespace, key);
+        self.clock += 1;
+        self.stats.hits += 1;
+        let entry = self.data.get_mut(&k).unwrap();
+        entry.last_used = self.clock;
+        let decoded: T = bincode::deserialize(&entry.data)
             .map_err(|e| anyhow!(e.to_string()))?;
         Ok(Some(decoded))
     }
+
+    pub fn remove(&mut self, namespace: &str, key: &str) {
+        if let Some(entry) = self.data.remove(&(namespace.to_string(), key.to_string())) {
+            self.bytes -= entry.data.len();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            entries: self.data.len(),
+            bytes: self.bytes,
+            ..self.stats
+        }
+    }
+
+    fn evict_expired(&mut self) {
+        let now = Instant::now();
+        let expired: Vec<(String, String)> = self
+            .data
+            .iter()
+            .filter(|(_, entry)| entry.expires_at <= now)
+            .map(|(k, _)| k.clone())
+            .collect();
+        for (namespace, key) in expired {
+            self.remove(&namespace, &key);
+            self.stats.expirations += 1;
+        }
+    }
+
+    fn evict_lru(&mut self) {
+        let lru = self
+            .data
+            .iter()
+            .min_by_key(|(_, entry)| entry.last_used)
+            .map(|(k, _)| k.clone());
+        if let Some((namespace, key)) = lru {
+            debug!("evicting cache object @ {}/{}", namespace, key);
+            self.remove(&namespace, &key);
+            self.stats.evictions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::Cache;
+
+    #[test]
+    fn test_get() {
+        let mut cache = Cache::with_limits(8, 1024);
+        cache.put("jwt", "a", &"alpha".to_string(), None).unwrap();
+        assert_eq!(Some("alpha".to_string()), cache.get::<String>("jwt", "a").unwrap());
+        // Keys are namespaced
+        assert_eq!(None, cache.get::<String>("opa", "a").unwrap());
+
+        let stats = cache.stats();
+        assert_eq!(1, stats.hits);
+        assert_eq!(1, stats.misses);
+        assert_eq!(1, stats.entries);
+        assert_eq!(0.5, stats.hit_rate());
+    }
+
+    #[test]
+    fn test_ttl_expiry() {
+        let mut cache = Cache::with_limits(8, 1024);
+        cache.put("jwt", "short", &1u32, Some(Duration::from_millis(10))).unwrap();
+        cache.put("jwt", "long", &2u32, Some(Duration::from_secs(60))).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        assert_eq!(None, cache.get::<u32>("jwt", "short").unwrap());
+        assert_eq!(Some(2), cache.get::<u32>("jwt", "long").unwrap());
+        let stats = cache.stats();
+        assert_eq!(1, stats.expirations);
+        assert_eq!(1, stats.misses);
+        assert_eq!(1, stats.entries);
+    }
+
+    #[test]
+    fn test_expired_entries_make_room() {
+        let mut cache = Cache::with_limits(2, 1024);
+        cache.put("jwt", "a", &1u32, Some(Duration::from_millis(10))).unwrap();
+        cache.put("jwt", "b", &2u32, None).unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+
+        // The expired entry is dropped rather than evicting a live one
+        cache.put("jwt", "c", &3u32, None).unwrap();
+        assert_eq!(Some(2), cache.get::<u32>("jwt", "b").unwrap());
+        assert_eq!(Some(3), cache.get::<u32>("jwt", "c").unwrap());
+        let stats = cache.stats();
+        assert_eq!(1, stats.expirations);
+        assert_eq!(0, stats.evictions);
+    }
+
+    #[test]
+    fn test_lru_eviction_by_entries() {
+        let mut cache = Cache::with_limits(2, 1024);
+        cache.put("jwt", "a", &1u32, None).unwrap();
+        cache.put("jwt", "b", &2u32, None).unwrap();
+        // Using `a` leaves `b` as the least recently used
+        assert_eq!(Some(1), cache.get::<u32>("jwt", "a").unwrap());
+        cache.put("jwt", "c", &3u32, None).unwrap();
+
+        assert_eq!(None, cache.get::<u32>("jwt", "b").unwrap());
+        assert_eq!(Some(1), cache.get::<u32>("jwt", "a").unwrap());
+        assert_eq!(Some(3), cache.get::<u32>("jwt", "c").unwrap());
+        let stats = cache.stats();
+        assert_eq!(1, stats.evictions);
+        assert_eq!(2, stats.entries);
+    }
+
+    #[test]
+    fn test_lru_eviction_by_bytes() {
+        // Each object encodes to its 8 byte length followed by its bytes
+        let mut cache = Cache::with_limits(8, 40);
+        cache.put("jwt", "a", &vec![0u8; 12], None).unwrap();
+        cache.put("jwt", "b", &vec![0u8; 12], None).unwrap();
+        assert_eq!(40, cache.stats().bytes);
+
+        cache.put("jwt", "c", &vec![0u8; 4], None).unwrap();
+        assert_eq!(None, cache.get::<Vec<u8>>("jwt", "a").unwrap());
+        assert!(cache.get::<Vec<u8>>("jwt", "b").unwrap().is_some());
+        let stats = cache.stats();
+        assert_eq!(1, stats.evictions);
+        assert_eq!(32, stats.bytes);
+
+        // An object larger than the whole cache isn't stored
+        cache.put("jwt", "d", &vec![0u8; 64], None).unwrap();
+        assert_eq!(None, cache.get::<Vec<u8>>("jwt", "d").unwrap());
+        assert_eq!(2, cache.stats().entries);
+    }
+
+    #[test]
+    fn test_replace() {
+        let mut cache = Cache::with_limits(2, 1024);
+        cache.put("jwt", "a", &1u32, None).unwrap();
+        cache.put("jwt", "a", &2u32, None).unwrap();
+        assert_eq!(Some(2), cache.get::<u32>("jwt", "a").unwrap());
+        let stats = cache.stats();
+        assert_eq!(1, stats.entries);
+        assert_eq!(4, stats.bytes);
+        assert_eq!(0, stats.evictions);
+    }
 }
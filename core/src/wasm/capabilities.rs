@@ -0,0 +1,225 @@
+//! WASI capabilities granted to a function guest.
+//!
+//! Capabilities are declared per function in `service.toml` and passed to the runtime as JSON,
+//! via `ASML_FUNCTION_CAPABILITIES`.
+
+use std::str::FromStr;
+
+use http::uri::Authority;
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Capabilities {
+    /// Host directories to preopen in the guest, in addition to `/tmp`
+    #[serde(default)]
+    pub preopens: Vec<Preopen>,
+    /// Names of the function environment variables visible to the guest.
+    /// When unset, all variables defined for the function are passed through.
+    pub env: Option<Vec<String>>,
+    #[serde(default)]
+    pub stdio: Stdio,
+    /// Allow the guest to open sockets and resolve names
+    #[serde(default)]
+    pub network: bool,
+    /// Outbound HTTP requests the guest may make through `wasi:http`; when unset, all are denied
+    pub http: Option<HttpCapability>,
+}
+
+impl Capabilities {
+    pub fn from_json(json: &str) -> anyhow::Result<Self> {
+        Ok(serde_json::from_str(json)?)
+    }
+
+    /// True if the guest may see the environment variable `name`
+    pub fn allows_env(&self, name: &str) -> bool {
+        match &self.env {
+            Some(allowed) => allowed.iter().any(|a| a == name),
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct HttpCapability {
+    /// Hosts which may be requested, optionally with a port (e.g. `api.example.com:8443`); without one,
+    /// only the scheme's default port is allowed. A leading `*.` matches any subdomain.
+    #[serde(default)]
+    pub hosts: Vec<String>,
+    /// Methods which may be used; when empty, any method is allowed
+    #[serde(default)]
+    pub methods: Vec<String>,
+    /// Schemes which may be used; when empty, only `https` is allowed
+    #[serde(default)]
+    pub schemes: Vec<String>,
+    /// Maximum number of requests per invocation
+    pub max_requests: Option<u32>,
+    /// Maximum time in milliseconds to connect, and to wait for each part of the response
+    pub timeout_ms: Option<u64>,
+}
+
+impl HttpCapability {
+    /// True if a request with `method` to `authority` (`host[:port]`) over `scheme` is allowed
+    pub fn allows(&self, scheme: &str, authority: &str, method: &str) -> bool {
+        let scheme_allowed = match self.schemes.is_empty() {
+            true => scheme.eq_ignore_ascii_case("https"),
+            false => self.schemes.iter().any(|s| s.eq_ignore_ascii_case(scheme)),
+        };
+        let method_allowed =
+            self.methods.is_empty() || self.methods.iter().any(|m| m.eq_ignore_ascii_case(method));
+        let authority = match Authority::from_str(authority) {
+            Ok(authority) => authority,
+            Err(_) => return false,
+        };
+        let port = authority.port_u16().or_else(|| default_port(scheme));
+        let host = authority.host().to_ascii_lowercase();
+        let host_allowed = self.hosts.iter().any(|h| {
+            let (wildcard, allowed) = match h.strip_prefix("*.") {
+                Some(domain) => (true, domain),
+                None => (false, h.as_str()),
+            };
+            let allowed = match Authority::from_str(allowed) {
+                Ok(allowed) => allowed,
+                Err(_) => return false,
+            };
+            let allowed_host = allowed.host().to_ascii_lowercase();
+            let host_matches = match wildcard {
+                true => host.ends_with(&format!(".{}", allowed_host)),
+                false => host == allowed_host,
+            };
+            host_matches && port.is_some() && allowed.port_u16().or_else(|| default_port(scheme)) == port
+        });
+        scheme_allowed && method_allowed && host_allowed
+    }
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme.to_ascii_lowercase().as_str() {
+        "https" => Some(443),
+        "http" => Some(80),
+        _ => None,
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Preopen {
+    pub host_path: String,
+    pub guest_path: String,
+    /// Preopens are read-only unless marked writable
+    #[serde(default)]
+    pub writable: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Stdio {
+    /// Guest stdout & stderr are logged line by line, tagged with the request id
+    #[default]
+    Capture,
+    /// Guest stdout & stderr are written to the host's
+    Inherit,
+    /// Guest output is discarded
+    Null,
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::wasm::capabilities::{Capabilities, HttpCapability, Stdio};
+
+    #[test]
+    fn test_defaults() {
+        let caps = Capabilities::from_json("{}").unwrap();
+        assert!(caps.preopens.is_empty());
+        assert!(caps.env.is_none());
+        assert_eq!(Stdio::Capture, caps.stdio);
+        assert!(!caps.network);
+        assert!(caps.http.is_none());
+        assert!(caps.allows_env("ANY_VARIABLE"));
+    }
+
+    #[test]
+    fn test_from_json() {
+        let caps = Capabilities::from_json(
+            r#"{
+                "preopens": [
+                    {"host_path": "/var/data", "guest_path": "/data"},
+                    {"host_path": "/var/out", "guest_path": "/out", "writable": true}
+                ],
+                "env": ["TABLE_NAME"],
+                "stdio": "null",
+                "network": true,
+                "http": {"hosts": ["api.example.com"], "max_requests": 10}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(2, caps.preopens.len());
+        assert!(!caps.preopens[0].writable);
+        assert!(caps.preopens[1].writable);
+        assert_eq!("/out", caps.preopens[1].guest_path);
+        assert!(caps.allows_env("TABLE_NAME"));
+        assert!(!caps.allows_env("AWS_SECRET_ACCESS_KEY"));
+        assert_eq!(Stdio::Null, caps.stdio);
+        assert!(caps.network);
+        let http = caps.http.unwrap();
+        assert_eq!(vec!["api.example.com".to_string()], http.hosts);
+        assert_eq!(Some(10), http.max_requests);
+        assert!(http.timeout_ms.is_none());
+    }
+
+    #[test]
+    fn test_invalid_json() {
+        assert!(Capabilities::from_json(r#"{"stdio": "file"}"#).is_err());
+        assert!(Capabilities::from_json(r#"{"preopens": [{"host_path": "/var/data"}]}"#).is_err());
+        assert!(Capabilities::from_json("not json").is_err());
+    }
+
+    #[test]
+    fn test_http_allows() {
+        let http = HttpCapability {
+            hosts: vec![
+                "api.example.com".to_string(),
+                "*.amazonaws.com".to_string(),
+                "internal.example.com:8443".to_string(),
+                "[::1]:8080".to_string(),
+            ],
+            methods: vec!["GET".to_string()],
+            ..Default::default()
+        };
+        assert!(http.allows("https", "api.example.com", "GET"));
+        assert!(http.allows("https", "API.example.com:443", "get"));
+        assert!(http.allows("https", "s3.us-east-1.amazonaws.com", "GET"));
+        assert!(!http.allows("https", "amazonaws.com", "GET"));
+        assert!(!http.allows("https", "evilamazonaws.com", "GET"));
+        // Only https is allowed when no schemes are given
+        assert!(!http.allows("http", "api.example.com", "GET"));
+        assert!(!http.allows("https", "api.example.com", "POST"));
+        assert!(!http.allows("https", "other.example.com", "GET"));
+        assert!(!http.allows("https", "", "GET"));
+    }
+
+    #[test]
+    fn test_http_allows_port() {
+        let http = HttpCapability {
+            hosts: vec![
+                "api.example.com".to_string(),
+                "internal.example.com:8443".to_string(),
+                "[::1]:8080".to_string(),
+            ],
+            schemes: vec!["https".to_string(), "http".to_string()],
+            ..Default::default()
+        };
+        // A host without a port is only allowed on its scheme's default port
+        assert!(http.allows("https", "api.example.com:443", "GET"));
+        assert!(http.allows("http", "api.example.com:80", "GET"));
+        assert!(!http.allows("https", "api.example.com:8443", "GET"));
+        assert!(!http.allows("http", "api.example.com:443", "GET"));
+
+        assert!(http.allows("https", "internal.example.com:8443", "GET"));
+        assert!(!http.allows("https", "internal.example.com", "GET"));
+        assert!(!http.allows("https", "internal.example.com:9443", "GET"));
+
+        // Bracketed IPv6 authorities keep their port
+        assert!(http.allows("http", "[::1]:8080", "GET"));
+        assert!(!http.allows("http", "[::1]:8081", "GET"));
+        assert!(!http.allows("http", "[::1]", "GET"));
+    }
+}
@@ -0,0 +1,211 @@
+//! On-disk cache of components compiled at load time.
+//!
+//! Entries are keyed by the hash of the component, the engine's compatibility hash (which covers
+//! the Wasmtime version, target and compiler settings) and the CPU compat mode. The cache directory
+//! may be shared between hosts, e.g. on a volume mounted by each replica; entries are written to a
+//! temporary file and renamed into place so that a partially written entry is never read.
+
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use once_cell::sync::Lazy;
+use ring::digest::{digest, Context, SHA256};
+use tracing::{debug, warn};
+use uuid::Uuid;
+use wasmtime::component::Component;
+use wasmtime::Engine;
+
+use crate::wasm::scratch::SCRATCH_ROOT;
+
+pub static COMPILE_CACHE_DIR: Lazy<Option<PathBuf>> = Lazy::new(|| {
+    match std::env::var("ASML_COMPILE_CACHE_DIR") {
+        Ok(dir) if dir.is_empty() => None,
+        Ok(dir) => Some(PathBuf::from(dir)),
+        Err(_) => Some(SCRATCH_ROOT.join("compiled")),
+    }
+});
+
+/// Total size of the entries kept in the cache; the least recently used entries are evicted past this size
+pub static COMPILE_CACHE_MAX_MB: Lazy<u64> = Lazy::new(|| {
+    std::env::var("ASML_COMPILE_CACHE_MAX_MB")
+        .ok()
+        .and_then(|mb| mb.parse::<u64>().ok())
+        .unwrap_or(1024)
+});
+
+const ENTRY_EXTENSION: &str = "cwasm";
+
+/// Load the component at `path` from the cache, compiling & caching it if there is no usable entry.
+/// Errors reading or writing the cache are logged and otherwise ignored.
+pub fn load_or_compile(engine: &Engine, path: &Path, cpu_compat_mode: &str) -> anyhow::Result<Component> {
+    let cache_dir = match COMPILE_CACHE_DIR.as_ref() {
+        Some(dir) => dir,
+        None => return Component::from_file(engine, path),
+    };
+
+    let wasm_bytes = fs::read(path)?;
+    let entry_path = cache_dir
+        .join(entry_key(engine, &wasm_bytes, cpu_compat_mode))
+        .with_extension(ENTRY_EXTENSION);
+
+    if entry_path.exists() {
+        // Safety: entries are only ever written by `Component::serialize` with an engine matching the key
+        match unsafe { Component::deserialize_file(engine, &entry_path) } {
+            Ok(component) => {
+                debug!("loaded {} from compile cache", path.display());
+                touch(&entry_path);
+                return Ok(component);
+            }
+            Err(err) => {
+                warn!("removing unusable compile cache entry {}: {}", entry_path.display(), err.to_string());
+                let _ = fs::remove_file(&entry_path);
+            }
+        }
+    }
+
+    let component = Component::new(engine, &wasm_bytes)?;
+    if let Err(err) = store(cache_dir, &entry_path, &component) {
+        warn!("could not write compile cache entry {}: {}", entry_path.display(), err.to_string());
+    }
+    Ok(component)
+}
+
+fn entry_key(engine: &Engine, wasm_bytes: &[u8], cpu_compat_mode: &str) -> String {
+    // Entries may be shared between hosts, so the engine part of the key must be stable across
+    // processes and builds, which rules out the std hashers
+    let mut engine_hasher = DigestHasher(Context::new(&SHA256));
+    engine.precompile_compatibility_hash().hash(&mut engine_hasher);
+    cpu_compat_mode.hash(&mut engine_hasher);
+
+    format!(
+        "{}-{}",
+        to_hex(digest(&SHA256, wasm_bytes).as_ref()),
+        to_hex(engine_hasher.0.finish().as_ref())
+    )
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Feeds anything implementing `Hash` into a SHA-256 digest
+struct DigestHasher(Context);
+
+impl Hasher for DigestHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        self.0.update(bytes);
+    }
+
+    fn finish(&self) -> u64 {
+        let digest = self.0.clone().finish();
+        u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap())
+    }
+}
+
+fn store(cache_dir: &Path, entry_path: &Path, component: &Component) -> anyhow::Result<()> {
+    fs::create_dir_all(cache_dir)?;
+    let bytes = component.serialize()?;
+    let tmp_path = cache_dir.join(format!(".{}.tmp", Uuid::new_v4()));
+    fs::write(&tmp_path, bytes)?;
+    fs::rename(&tmp_path, entry_path)?;
+    debug!("wrote compile cache entry {}", entry_path.display());
+
+    evict(cache_dir, *COMPILE_CACHE_MAX_MB * 1024 * 1024)?;
+    Ok(())
+}
+
+/// Mark an entry as recently used
+fn touch(entry_path: &Path) {
+    if let Ok(file) = fs::File::options().append(true).open(entry_path) {
+        let _ = file.set_modified(SystemTime::now());
+    }
+}
+
+/// Remove the least recently used entries until the cache is no larger than `max_bytes`
+fn evict(cache_dir: &Path, max_bytes: u64) -> io::Result<()> {
+    let mut entries = fs::read_dir(cache_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension().map_or(false, |ext| ext == ENTRY_EXTENSION))
+        .filter_map(|entry| {
+            let metadata = entry.metadata().ok()?;
+            Some((entry.path(), metadata.len(), metadata.modified().ok()?))
+        })
+        .collect::<Vec<(PathBuf, u64, SystemTime)>>();
+
+    let mut total: u64 = entries.iter().map(|e| e.1).sum();
+    entries.sort_by_key(|e| e.2);
+    for (path, size, _) in entries {
+        if total <= max_bytes {
+            break;
+        }
+        debug!("evicting compile cache entry {}", path.display());
+        // Another host sharing the cache may have evicted the entry already
+        match fs::remove_file(&path) {
+            Ok(_) => total -= size,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => total -= size,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::time::{Duration, SystemTime};
+
+    use uuid::Uuid;
+    use wasmtime::Engine;
+
+    use super::{entry_key, evict};
+
+    fn write_entry(dir: &Path, name: &str, size: usize, age_secs: u64) {
+        let path = dir.join(name);
+        fs::write(&path, vec![0u8; size]).unwrap();
+        let file = fs::File::options().append(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age_secs))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_evict_least_recently_used() {
+        let dir = std::env::temp_dir().join(format!("asml-compile-cache-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        write_entry(&dir, "oldest.cwasm", 100, 300);
+        write_entry(&dir, "older.cwasm", 100, 200);
+        write_entry(&dir, "newest.cwasm", 100, 100);
+        // Files which aren't entries are neither counted nor evicted
+        write_entry(&dir, ".partial.tmp", 1000, 400);
+
+        evict(&dir, 250).unwrap();
+        assert!(!dir.join("oldest.cwasm").exists());
+        assert!(dir.join("older.cwasm").exists());
+        assert!(dir.join("newest.cwasm").exists());
+        assert!(dir.join(".partial.tmp").exists());
+
+        evict(&dir, 100).unwrap();
+        assert!(!dir.join("older.cwasm").exists());
+        assert!(dir.join("newest.cwasm").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_entry_key() {
+        let engine = Engine::default();
+        let key = entry_key(&engine, b"component", "default");
+        assert_eq!(key, entry_key(&engine, b"component", "default"));
+        assert_ne!(key, entry_key(&engine, b"other component", "default"));
+        assert_ne!(key, entry_key(&engine, b"component", "native"));
+
+        // Both parts are full SHA-256 digests
+        let (component_hash, engine_hash) = key.split_once('-').unwrap();
+        assert_eq!(64, component_hash.len());
+        assert_eq!(64, engine_hash.len());
+        assert!(engine_hash.chars().all(|c| c.is_ascii_hexdigit()));
+    }
+}
@@ -0,0 +1,189 @@
+//! Structured reports of guests which trap or otherwise fail.
+//!
+//! Frames are symbolicated from the component's name section, and from its DWARF debug info when
+//! the guest was built with it, since engines are configured with `WasmBacktraceDetails::Enable`.
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use wasmtime::{FrameInfo, Trap, WasmBacktrace};
+
+/// When true, the crash report is added to the body of a failed invocation.
+/// Reports expose the function's internals, and should not be returned in production.
+pub static RETURN_CRASH_REPORTS: Lazy<bool> = Lazy::new(|| {
+    std::env::var("ASML_RETURN_CRASH_REPORTS")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+});
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CrashReport {
+    pub coordinates: Option<String>,
+    pub request_id: Option<String>,
+    /// The trap code, e.g. `unreachable`, if the guest trapped
+    pub trap: Option<String>,
+    pub message: String,
+    /// Innermost frame first
+    pub frames: Vec<CrashFrame>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CrashFrame {
+    pub module: Option<String>,
+    pub func_index: u32,
+    pub func_name: Option<String>,
+    pub module_offset: Option<usize>,
+    pub file: Option<String>,
+    pub line: Option<u32>,
+    pub column: Option<u32>,
+}
+
+impl CrashReport {
+    pub fn new(err: &anyhow::Error, coordinates: Option<String>, request_id: Option<String>) -> Self {
+        let frames = err
+            .downcast_ref::<WasmBacktrace>()
+            .map(|backtrace| backtrace.frames().iter().flat_map(frames_of).collect())
+            .unwrap_or_default();
+        Self {
+            coordinates,
+            request_id,
+            trap: err.downcast_ref::<Trap>().map(trap_code),
+            message: err.root_cause().to_string(),
+            frames,
+        }
+    }
+
+    /// The body of the invocation's failure response. The report itself is only included if
+    /// `include_report`, i.e. if enabled by `ASML_RETURN_CRASH_REPORTS`.
+    pub fn response_body(&self, include_report: bool) -> String {
+        let message = format!("WASM module exited in error: {}", self.message);
+        match include_report {
+            true => serde_json::json!({ "error": message, "crash": self }).to_string(),
+            false => message,
+        }
+    }
+
+    /// Log the report as a single line of JSON, under the `crash` target
+    pub fn log(&self) {
+        match serde_json::to_string(self) {
+            Ok(json) => tracing::error!(target: "crash", "{}", json),
+            Err(err) => tracing::error!("could not serialize crash report: {}", err.to_string()),
+        }
+    }
+}
+
+/// A frame has a symbol for each function inlined at its location; without debug info, it has none
+fn frames_of(frame: &FrameInfo) -> Vec<CrashFrame> {
+    let module = frame.module().name().map(String::from);
+    let func_name = frame.func_name().map(demangle);
+    if frame.symbols().is_empty() {
+        return vec![CrashFrame {
+            module,
+            func_index: frame.func_index(),
+            func_name,
+            module_offset: frame.module_offset(),
+            file: None,
+            line: None,
+            column: None,
+        }];
+    }
+    frame
+        .symbols()
+        .iter()
+        .map(|symbol| CrashFrame {
+            module: module.clone(),
+            func_index: frame.func_index(),
+            func_name: symbol.name().map(demangle).or(func_name.clone()),
+            module_offset: frame.module_offset(),
+            file: symbol.file().map(String::from),
+            line: symbol.line(),
+            column: symbol.column(),
+        })
+        .collect()
+}
+
+fn demangle(name: &str) -> String {
+    match rustc_demangle::try_demangle(name) {
+        Ok(demangled) => format!("{:#}", demangled),
+        Err(_) => name.to_string(),
+    }
+}
+
+fn trap_code(trap: &Trap) -> String {
+    match trap {
+        Trap::StackOverflow => "stack_overflow",
+        Trap::MemoryOutOfBounds => "memory_out_of_bounds",
+        Trap::HeapMisaligned => "heap_misaligned",
+        Trap::TableOutOfBounds => "table_out_of_bounds",
+        Trap::IndirectCallToNull => "indirect_call_to_null",
+        Trap::BadSignature => "bad_signature",
+        Trap::IntegerOverflow => "integer_overflow",
+        Trap::IntegerDivisionByZero => "integer_division_by_zero",
+        Trap::BadConversionToInteger => "bad_conversion_to_integer",
+        Trap::UnreachableCodeReached => "unreachable",
+        Trap::Interrupt => "interrupt",
+        Trap::AlwaysTrapAdapter => "always_trap_adapter",
+        Trap::OutOfFuel => "out_of_fuel",
+        Trap::AtomicWaitNonSharedMemory => "atomic_wait_non_shared_memory",
+        _ => "unknown",
+    }
+    .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::anyhow;
+    use wasmtime::{Engine, Instance, Module, Store, Trap};
+
+    use super::{demangle, trap_code, CrashReport};
+
+    #[test]
+    fn test_trap_code() {
+        assert_eq!("unreachable", trap_code(&Trap::UnreachableCodeReached));
+        assert_eq!("interrupt", trap_code(&Trap::Interrupt));
+        assert_eq!("integer_division_by_zero", trap_code(&Trap::IntegerDivisionByZero));
+    }
+
+    #[test]
+    fn test_demangle() {
+        assert_eq!(
+            "core::panicking::panic",
+            demangle("_ZN4core9panicking5panic17h0123456789abcdefE")
+        );
+        // Names which aren't mangled are kept as they are
+        assert_eq!("run", demangle("run"));
+    }
+
+    #[test]
+    fn test_report_error() {
+        let err = anyhow!("no space left on device").context("could not write /tmp/out");
+        let report = CrashReport::new(&err, Some("project.service.function".into()), None);
+        assert_eq!("no space left on device", report.message);
+        assert!(report.trap.is_none());
+        assert!(report.frames.is_empty());
+    }
+
+    #[test]
+    fn test_report_trap() {
+        let engine = Engine::default();
+        let wat = r#"(module (func $boom (export "run") unreachable))"#;
+        let module = Module::new(&engine, wat).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Instance::new(&mut store, &module, &[]).unwrap();
+        let run = instance.get_typed_func::<(), ()>(&mut store, "run").unwrap();
+        let err = run.call(&mut store, ()).unwrap_err();
+
+        let report = CrashReport::new(&err, None, Some("request".into()));
+        assert_eq!(Some("unreachable"), report.trap.as_deref());
+        assert_eq!(Some("boom"), report.frames[0].func_name.as_deref());
+    }
+
+    #[test]
+    fn test_response_body() {
+        let report = CrashReport::new(&anyhow!("oops"), None, None);
+        assert_eq!("WASM module exited in error: oops", report.response_body(false));
+
+        let body: serde_json::Value = serde_json::from_str(&report.response_body(true)).unwrap();
+        assert_eq!("WASM module exited in error: oops", body["error"]);
+        assert_eq!("oops", body["crash"]["message"]);
+    }
+}
@@ -1,10 +1,19 @@
-mod cache;
+pub mod cache;
+pub mod capabilities;
+pub mod compile_cache;
+pub mod crash;
+pub mod outbound;
+pub mod scratch;
+pub mod stdio;
 
 use std::borrow::Cow;
+use std::collections::HashMap;
+use std::fmt;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::string::ToString;
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
 
 use anyhow::{anyhow, Context};
 use wasmtime_wasi::sync::Dir;
@@ -12,16 +21,21 @@ use wasmtime_wasi::preview2;
 use wasmtime_wasi::preview2::{DirPerms, FilePerms, WasiView};
 pub use crossbeam_channel::bounded as status_channel;
 use once_cell::sync::Lazy;
-use tracing::debug;
+use tracing::{debug, warn};
 use uuid::Uuid;
-use wasmtime::{component, AsContextMut, AsContext};
-use wasmtime::component::{Component, ResourceTable};
-use wasmtime::{Config, Engine, Store};
+use wasmtime::{AsContextMut, AsContext};
+use wasmtime::component::{Component, InstancePre, Linker, Resource, ResourceTable, TypedFunc};
+use wasmtime::{
+    Config, Engine, InstanceAllocationStrategy, PoolingAllocationConfig, ResourceLimiter, Store,
+    Trap, UpdateDeadline,
+};
+use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequest};
+use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
 use wit_component::{ComponentEncoder, StringEncoding};
 use wasm_encoder::{Encode, Section};
 use wit_parser::{PackageId, Resolve, UnresolvedPackage, WorldId};
 
-use assemblylift_core_iomod::registry::RegistryTx;
+use assemblylift_core_iomod::registry::{RegistryTx, UNAVAILABLE};
 
 pub use asml_wit::akkoro::assemblylift::asml_io;
 pub use asml_wit::akkoro::assemblylift::asml_rt;
@@ -31,29 +45,196 @@ use secrets_wit::akkoro::secrets::secret_storage;
 
 use crate::jwt::keyset::KeyStore as JwtKeyStore;
 use crate::policy_manager::PolicyManager;
-use crate::threader::Threader;
-use crate::wasm::cache::Cache;
+use crate::runtime_environment;
+use crate::telemetry;
+use crate::threader::{IomodVersions, Threader};
+use crate::wasm::cache::{Cache, CacheStats};
+use crate::wasm::capabilities::{Capabilities, Stdio};
+use crate::wasm::crash::{CrashReport, RETURN_CRASH_REPORTS};
+use crate::wasm::outbound::OutboundHttp;
+use crate::wasm::scratch::ScratchDir;
+use crate::wasm::stdio::{CapturedOutput, RETURN_STDIO_ON_FAILURE};
 use crate::RuntimeAbi;
 
 // pub type State<R, S> = AsmlFunctionState<R, S>;
 pub type StatusTx<S> = crossbeam_channel::Sender<S>;
 pub type StatusRx<S> = crossbeam_channel::Receiver<S>;
 
+/// The interface exported by reactor components
+pub const HANDLER_INTERFACE: &str = "akkoro:assemblylift/handler";
+
+/// Cache namespace holding JWKS keysets, keyed by their URL
+const JWT_KEYSET_NAMESPACE: &str = "jwt.keyset";
+
+/// `handle` from the `handler` interface; its `http-response` has the same shape as asml-rt's
+pub type HandlerFunc = TypedFunc<(Vec<u8>,), (asml_rt::HttpResponse,)>;
+
+pub type FunctionStore<R, S> = Store<AsmlComponentFunctionState<R, S>>;
+
+/// Progress of a response sent with `begin-response`, `write-chunk` & `finish-response`
+enum ResponseStream {
+    NotStarted,
+    Streaming,
+    /// The runtime can't stream; the response is sent once finished
+    Buffering(asml_rt::HttpResponse),
+    Finished,
+}
+
+/// How an instance is invoked
+pub enum Entrypoint {
+    /// `wasi:cli/run`, which runs once per instance
+    Command(preview2::command::Command),
+    /// `handler.handle`, which may be called for any number of invocations on the same instance
+    Reactor(HandlerFunc),
+}
+
 pub static CPU_COMPAT_MODE: Lazy<String> =
     Lazy::new(|| std::env::var("ASML_CPU_COMPAT_MODE").unwrap_or("default".to_string()));
 
-mod asml_wit { wasmtime::component::bindgen!("assemblylift" in "wit/assemblylift"); }
+/// When set, engines use the pooling instance allocator with this many component instance slots.
+/// Pooling trades a large up-front virtual memory reservation for faster instantiation.
+pub static POOLING_INSTANCES: Lazy<Option<u32>> = Lazy::new(|| {
+    std::env::var("ASML_POOLING_INSTANCES")
+        .ok()
+        .and_then(|n| n.parse::<u32>().ok())
+});
+
+/// Interval at which the engine epoch is incremented. Running guests yield to the host
+/// once per tick, which is also the granularity at which timeouts are enforced.
+pub const EPOCH_TICK: Duration = Duration::from_millis(10);
+
+// Limits on the number of WASM resources a single function may create
+const MAX_TABLE_ELEMENTS: u32 = 20_000;
+const MAX_INSTANCES: usize = 1_000;
+const MAX_TABLES: usize = 1_000;
+const MAX_MEMORIES: usize = 1_000;
+
+// Number of epoch ticks between checks of the scratch directory against its quota; writes
+// between two checks aren't limited, which makes the quota best-effort
+const TMP_QUOTA_CHECK_TICKS: u64 = 10;
+
+// Size of each linear memory slot reserved by the pooling allocator
+const POOLING_MEMORY_PAGES: u64 = 16_384; // 1 GiB
+
+mod asml_wit {
+    wasmtime::component::bindgen!({
+        world: "assemblylift",
+        path: "wit/assemblylift",
+        // Streamed responses wait for the runtime to accept each part
+        async: {
+            only_imports: ["begin-response", "write-chunk", "finish-response"],
+        },
+        with: {
+            "wasi:io/poll": wasmtime_wasi::preview2::bindings::io::poll,
+        },
+    });
+}
 mod jwt_wit { wasmtime::component::bindgen!("jwt" in "wit/jwt"); }
 mod opa_wit { wasmtime::component::bindgen!("opa" in "wit/opa"); }
 mod secrets_wit { wasmtime::component::bindgen!("secrets" in "wit/secrets"); }
 // bindgen!("wasi-secrets" in "components/wasi-secrets/wit");
+
+/// Resource limits applied to a single function invocation
+#[derive(Clone, Debug, Default)]
+pub struct FunctionLimits {
+    /// Wall-clock time the guest may run before it is interrupted
+    pub timeout: Option<Duration>,
+    /// Maximum total size in megabytes of the guest's linear memories
+    pub memory_size_mb: Option<u32>,
+    /// Maximum total size in megabytes of the files in the guest's `/tmp`
+    pub tmp_quota_mb: Option<u32>,
+    /// Time an IOmod call may take to respond, unless the guest sets a timeout for the call
+    pub iomod_timeout: Option<Duration>,
+}
+
+/// Returns true if `err` is the result of a guest being interrupted at its deadline
+pub fn is_timeout(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<Trap>(), Some(Trap::Interrupt))
+}
+
+/// Returns true if `err` is the result of a guest exceeding one of its `FunctionLimits`.
+/// In that case the failure has already been reported through `RuntimeAbi::failure`.
+pub fn is_limit_exceeded(err: &anyhow::Error) -> bool {
+    err.downcast_ref::<LimitExceeded>().is_some()
+}
+
+#[derive(Debug)]
+pub enum LimitExceeded {
+    Memory { limit_mb: u32 },
+    TmpQuota { limit_mb: u32 },
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitExceeded::Memory { limit_mb } => {
+                write!(f, "memory limit exceeded ({} MB)", limit_mb)
+            }
+            LimitExceeded::TmpQuota { limit_mb } => {
+                write!(f, "/tmp quota exceeded ({} MB)", limit_mb)
+            }
+        }
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// An invocation which could not be started, e.g. because its request was malformed or its
+/// component could not be loaded. Runtimes report these instead of running the function.
+#[derive(Debug)]
+pub enum InvocationError {
+    /// The request is malformed, e.g. a header is missing or invalid
+    BadRequest(String),
+    /// There is no function component at the requested path or coordinates
+    NotFound(String),
+    /// The host can't run functions at the moment, e.g. its scratch directory is missing
+    Unavailable(String),
+    /// The function's deployed configuration is invalid, e.g. its capabilities can't be parsed
+    BadConfig(String),
+    /// The function's component couldn't be loaded, linked or instantiated
+    Failed(anyhow::Error),
+}
+
+impl InvocationError {
+    /// The HTTP status code for responses to the failed invocation
+    pub fn status_code(&self) -> u16 {
+        match self {
+            InvocationError::BadRequest(_) => 400,
+            InvocationError::NotFound(_) => 404,
+            InvocationError::Unavailable(_) => 503,
+            InvocationError::BadConfig(_) | InvocationError::Failed(_) => 500,
+        }
+    }
+}
+
+impl fmt::Display for InvocationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InvocationError::BadRequest(msg) => write!(f, "bad request: {}", msg),
+            InvocationError::NotFound(msg) => write!(f, "function not found: {}", msg),
+            InvocationError::Unavailable(msg) => write!(f, "runtime unavailable: {}", msg),
+            InvocationError::BadConfig(msg) => write!(f, "invalid function configuration: {}", msg),
+            InvocationError::Failed(err) => write!(f, "could not start function: {:#}", err),
+        }
+    }
+}
+
+impl std::error::Error for InvocationError {}
+
+impl From<anyhow::Error> for InvocationError {
+    fn from(err: anyhow::Error) -> Self {
+        InvocationError::Failed(err)
+    }
+}
+
 pub struct Wasmtime<R, S>
 where
     R: RuntimeAbi<S> + Send + 'static,
     S: Clone + Send + Sized + 'static,
 {
     engine: Engine,
-    component: Component,
+    _epoch_tick: EpochTick,
+    instance_pre: InstancePre<AsmlComponentFunctionState<R, S>>,
     cache: Arc<Mutex<Cache>>,
     _phantom_r: std::marker::PhantomData<R>,
     _phantom_s: std::marker::PhantomData<S>,
@@ -64,33 +245,88 @@ where
     R: RuntimeAbi<S> + Send + 'static,
     S: Clone + Send + Sized + 'static,
 {
-    fn new_component(path: &Path) -> anyhow::Result<(Engine, Component)> {
-        match path.extension().unwrap().to_str().unwrap() {
+    fn new_component(path: &Path) -> Result<(Engine, Component), InvocationError> {
+        // A precompiled artifact which is missing may still be compiled from its component
+        let has_component = path.extension().map_or(false, |ext| ext == "bin") && path.with_extension("").exists();
+        if !path.exists() && !has_component {
+            return Err(InvocationError::NotFound(path.display().to_string()));
+        }
+        match path.extension().and_then(|ext| ext.to_str()).unwrap_or_default() {
             "bin" => {
                 let target = Self::get_target();
                 let engine = new_engine(target, None)?;
-                let component = unsafe { Component::deserialize_file(&engine, path) }
-                    .expect("could not deserialize component");
-                Ok((engine, component))
+                // Prefer the artifact built for this host's architecture, if one was shipped alongside
+                let wasm_path = path.with_extension("");
+                let bin_path = target
+                    .map(|target| precompiled_path(&wasm_path, target))
+                    .filter(|bin_path| bin_path.exists())
+                    .unwrap_or(path.to_path_buf());
+                match unsafe { Component::deserialize_file(&engine, &bin_path) } {
+                    Ok(component) => Ok((engine, component)),
+                    // The artifact was built for another target, CPU or Wasmtime version
+                    Err(err) if wasm_path.exists() => {
+                        warn!(
+                            "could not load precompiled component {}, compiling {} instead: {}",
+                            bin_path.display(),
+                            wasm_path.display(),
+                            err.to_string()
+                        );
+                        let engine = new_engine(None, None)?;
+                        let component = compile_cache::load_or_compile(&engine, &wasm_path, &CPU_COMPAT_MODE)
+                            .context("could not compile component")?;
+                        Ok((engine, component))
+                    }
+                    Err(err) => Err(err
+                        .context(format!("could not deserialize component {}", bin_path.display()))
+                        .into()),
+                }
             }
             "wasm" => {
                 let engine = new_engine(None, None)?;
-                let component = Component::from_file(&engine, path)
-                    .expect("could not deserialize component");
+                let component = compile_cache::load_or_compile(&engine, path, &CPU_COMPAT_MODE)
+                    .context("could not compile component")?;
                 Ok((engine, component))
             }
             _ => {
                 Err(anyhow!(
                     "invalid module extension; must be .wasm or .wasm.bin"
-                ))
+                ).into())
             }
         }
     }
 
+    /// Link the host interfaces once per component; the linker is only needed to build the `InstancePre`
+    fn new_instance_pre(
+        engine: &Engine,
+        component: &Component,
+    ) -> anyhow::Result<InstancePre<AsmlComponentFunctionState<R, S>>> {
+        let mut linker: Linker<AsmlComponentFunctionState<R, S>> = Linker::new(engine);
+
+        wasmtime_wasi::preview2::command::add_to_linker(&mut linker)
+            .context("could not link wasi runtime component")?;
+        wasmtime_wasi_http::proxy::add_only_http_to_linker(&mut linker)
+            .context("could not link wasi http runtime component")?;
+        // Linked by interface, as wasi:io/poll is already linked by the WASI command
+        asml_io::add_to_linker(&mut linker, |s| s)
+            .context("could not link assemblylift runtime component")?;
+        asml_rt::add_to_linker(&mut linker, |s| s)
+            .context("could not link assemblylift runtime component")?;
+        jwt_wit::Jwt::add_to_linker(&mut linker, |s| s)
+            .context("could not link jwt runtime component")?;
+        opa_wit::Opa::add_to_linker(&mut linker, |s| s)
+            .context("could not link opa runtime component")?;
+        secrets_wit::Secrets::add_to_linker(&mut linker, |s| s)
+            .context("could not link secrets runtime component")?;
+
+        linker.instantiate_pre(component)
+    }
+
     fn get_target() -> Option<&'static str> {
-        match std::env::consts::OS {
-            "macos" => Some("x86_64-apple-darwin"),
-            "linux" => Some("x86_64-linux-gnu"),
+        match (std::env::consts::OS, std::env::consts::ARCH) {
+            ("macos", "x86_64") => Some("x86_64-apple-darwin"),
+            ("macos", "aarch64") => Some("aarch64-apple-darwin"),
+            ("linux", "x86_64") => Some("x86_64-linux-gnu"),
+            ("linux", "aarch64") => Some("aarch64-linux-gnu"),
             _ => None,
         }
     }
@@ -101,78 +337,120 @@ where
     R: RuntimeAbi<S> + Send + 'static,
     S: Clone + Send + Sized + 'static,
 {
-    pub fn new_from_path(path: &Path) -> anyhow::Result<Self> {
-        let ec = Self::new_component(path);
-        match ec {
-            Ok(ec) => Ok(Self {
-                engine: ec.0,
-                component: ec.1,
-                cache: Arc::new(Mutex::new(Cache::new())),
-                _phantom_r: Default::default(),
-                _phantom_s: Default::default(),
-            }),
-            Err(err) => Err(anyhow!(err)),
-        }
+    pub fn new_from_path(path: &Path) -> Result<Self, InvocationError> {
+        let (engine, component) = Self::new_component(path)?;
+        Ok(Self {
+            instance_pre: Self::new_instance_pre(&engine, &component)?,
+            _epoch_tick: EpochTick::new(&engine),
+            engine,
+            cache: Arc::new(Mutex::new(Cache::new())),
+            _phantom_r: Default::default(),
+            _phantom_s: Default::default(),
+        })
+    }
+
+    /// Usage of the object cache shared by this function's instances
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.lock().unwrap().stats()
     }
 
     pub async fn link_wasi_component(
-        &mut self,
+        &self,
         registry_tx: RegistryTx,
         status_tx: StatusTx<S>,
         environment_vars: Vec<(String, String)>,
         runtime_environment: String,
         bind_paths: Vec<(String, String)>,
+        capabilities: &Capabilities,
+        limits: FunctionLimits,
+        iomod_versions: IomodVersions,
         request_id: Option<String>,
         input: &[u8],
-    ) -> anyhow::Result<(
-        preview2::command::Command,
-        Store<AsmlComponentFunctionState<R, S>>,
-    )> {
-        let threader = Arc::new(Mutex::new(Threader::new(registry_tx)));
-        let mut linker: component::Linker<AsmlComponentFunctionState<R, S>> = component::Linker::new(&self.engine);
-
-        wasmtime_wasi::preview2::command::add_to_linker(&mut linker)
-            .expect("could not link wasi runtime component");
-        asml_wit::Assemblylift::add_to_linker(&mut linker, |s| s)
-            .expect("could not link assemblylift runtime component");
-        jwt_wit::Jwt::add_to_linker(&mut linker, |s| s)
-            .expect("could not link jwt runtime component");
-        opa_wit::Opa::add_to_linker(&mut linker, |s| s)
-            .expect("could not link opa runtime component");
-        secrets_wit::Secrets::add_to_linker(&mut linker, |s| s)
-            .expect("could not link secrets runtime component");
+    ) -> Result<(Entrypoint, FunctionStore<R, S>), InvocationError> {
+        let threader = Arc::new(Mutex::new(Threader::new(
+            registry_tx,
+            limits.iomod_timeout,
+            iomod_versions,
+        )));
 
         let mut builder = &mut preview2::WasiCtxBuilder::new();
         for e in environment_vars {
-            builder = builder.env(&*e.0, &*e.1);
+            if capabilities.allows_env(&e.0) {
+                builder = builder.env(&*e.0, &*e.1);
+            }
         }
 
-        match runtime_environment.as_str() {
-            "ruby" => builder = builder.inherit_stdio().env("RUBY_PLATFORM", "wasm32-wasi").args(&["ruby", "/src/handler.rb"]),
-            _ => (),
+        if let Some(environment) = runtime_environment::by_name(&runtime_environment) {
+            for (name, value) in environment.env {
+                builder = builder.env(name, value);
+            }
+            if !environment.args.is_empty() {
+                builder = builder.args(environment.args);
+            }
         }
 
+        // Paths bound by the runtime itself (e.g. for an interpreter) are never writable.
+        // Bind paths & preopens are deployed with the function, so one which can't be opened is a config error.
         for path in bind_paths {
-            debug!("binding {} to {}", path.0, path.1);
+            debug!("binding {} to {} (read-only)", path.0, path.1);
             builder = builder.preopened_dir(
-                Dir::from_std_file(
-                    File::open(path.0).unwrap(),
-                ),
-                DirPerms::all(), 
-                FilePerms::all(),
+                Dir::from_std_file(File::open(&path.0).map_err(|err| {
+                    InvocationError::BadConfig(format!("could not open bind path {}: {}", path.0, err))
+                })?),
+                DirPerms::READ,
+                FilePerms::READ,
                 path.1,
             );
         }
+        for preopen in &capabilities.preopens {
+            debug!("binding {} to {} (writable: {})", preopen.host_path, preopen.guest_path, preopen.writable);
+            let (dir_perms, file_perms) = match preopen.writable {
+                true => (DirPerms::all(), FilePerms::all()),
+                false => (DirPerms::READ, FilePerms::READ),
+            };
+            builder = builder.preopened_dir(
+                Dir::from_std_file(File::open(&preopen.host_path).map_err(|err| {
+                    InvocationError::BadConfig(format!("could not open preopen {}: {}", preopen.host_path, err))
+                })?),
+                dir_perms,
+                file_perms,
+                &preopen.guest_path,
+            );
+        }
+        // The scratch root is created when the runtime starts; without it no function can run
+        let scratch_dir = ScratchDir::new(limits.tmp_quota_mb).map_err(|err| {
+            InvocationError::Unavailable(format!("could not create scratch directory: {}", err))
+        })?;
         builder = builder.preopened_dir(
             Dir::from_std_file(
-                File::open("/tmp/asmltmp").unwrap(),
+                File::open(scratch_dir.path()).map_err(|err| {
+                    InvocationError::Unavailable(format!("could not open scratch directory: {}", err))
+                })?,
             ),
             DirPerms::all(), 
             FilePerms::all(),
             "/tmp",
         );
-        builder = builder.inherit_stdout();
-        builder = builder.inherit_stderr();
+
+        let captured_stdio = match capabilities.stdio {
+            Stdio::Capture => {
+                let stdout = CapturedOutput::new(stdio::Stream::Stdout, request_id.clone());
+                let stderr = CapturedOutput::new(stdio::Stream::Stderr, request_id.clone());
+                builder = builder.stdout(stdout.clone());
+                builder = builder.stderr(stderr.clone());
+                Some((stdout, stderr))
+            }
+            Stdio::Inherit => {
+                builder = builder.inherit_stdout();
+                builder = builder.inherit_stderr();
+                None
+            }
+            Stdio::Null => None,
+        };
+        if capabilities.network {
+            builder = builder.inherit_network();
+            builder = builder.allow_ip_name_lookup(true);
+        }
 
         let table = ResourceTable::new();
         let wasi = builder.build();
@@ -184,26 +462,56 @@ where
             threader,
             request_id,
             cache: self.cache.clone(),
+            memory_size_mb: limits.memory_size_mb,
+            memory_bytes: 0,
+            deadline: limits.timeout.map(|timeout| Instant::now() + timeout),
+            response_stream: ResponseStream::NotStarted,
+            scratch_dir,
+            captured_stdio,
+            outbound_http: OutboundHttp::new(capabilities.http.clone()),
+            http: WasiHttpCtx,
             wasi,
             table,
             _phantom: Default::default(),
         };
         let mut store = Store::new(&self.engine, state);
+        store.limiter(|state| state);
 
-        match wasmtime_wasi::preview2::command::Command::instantiate_async(
-            &mut store,
-            &self.component,
-            &linker,
-        )
-        .await
-        {
-            Ok((wasi, _instance)) => Ok((wasi, store)),
-            Err(err) => Err(anyhow!(err)),
-        }
+        // The guest yields back to the executor on every epoch tick, so that a spinning guest
+        // can't starve the host; once past its deadline the guest traps with `Trap::Interrupt`.
+        let mut ticks: u64 = 0;
+        store.set_epoch_deadline(1);
+        store.epoch_deadline_callback(move |ctx| {
+            if let Some(deadline) = ctx.data().deadline {
+                if Instant::now() >= deadline {
+                    return Err(Trap::Interrupt.into());
+                }
+            }
+            ticks += 1;
+            if ticks % TMP_QUOTA_CHECK_TICKS == 0 {
+                if let Err(err) = ctx.data().scratch_dir.check_quota() {
+                    return Err(ctx.data().limit_exceeded(err));
+                }
+            }
+            Ok(UpdateDeadline::Yield(1))
+        });
+
+        let instance = self.instance_pre.instantiate_async(&mut store).await?;
+        let handler = instance
+            .exports(&mut store)
+            .instance(HANDLER_INTERFACE)
+            .map(|mut handler| handler.typed_func::<(Vec<u8>,), (asml_rt::HttpResponse,)>("handle"))
+            .transpose()
+            .context("reactor component exports an incompatible handler")?;
+        let entrypoint = match handler {
+            Some(handler) => Entrypoint::Reactor(handler),
+            None => Entrypoint::Command(preview2::command::Command::new(&mut store, &instance)?),
+        };
+        Ok((entrypoint, store))
     }
 
     pub async fn run_component<CTX>(
-        &mut self,
+        &self,
         wasi: wasmtime_wasi::preview2::command::Command,
         mut store: CTX,
     ) -> anyhow::Result<()>
@@ -217,6 +525,25 @@ where
             .await?
             .map_err(|()| anyhow::anyhow!("command returned with failing exit status"))
     }
+
+    /// Call a reactor's handler with the input of the store's current invocation, and report its response.
+    /// An instance which returns an error must not be called again.
+    pub async fn call_handler(
+        &self,
+        handler: &HandlerFunc,
+        store: &mut FunctionStore<R, S>,
+    ) -> anyhow::Result<()> {
+        let input = store.data().function_input.clone();
+        let (response,) = handler.call_async(&mut *store, (input,)).await?;
+        handler.post_return_async(&mut *store).await?;
+
+        // A handler which streamed its response has already sent it
+        let state = store.data();
+        if let ResponseStream::NotStarted = state.response_stream {
+            R::respond(state.status_sender.clone(), response, state.request_id.clone());
+        }
+        Ok(())
+    }
 }
 
 pub struct AsmlComponentFunctionState<R, S>
@@ -230,6 +557,15 @@ where
     function_input: Vec<u8>,
     request_id: Option<String>,
     cache: Arc<Mutex<Cache>>,
+    memory_size_mb: Option<u32>,
+    // Total size of the store's linear memories, which is checked against `memory_size_mb`
+    memory_bytes: usize,
+    deadline: Option<Instant>,
+    response_stream: ResponseStream,
+    scratch_dir: ScratchDir,
+    captured_stdio: Option<(CapturedOutput, CapturedOutput)>,
+    outbound_http: OutboundHttp,
+    http: WasiHttpCtx,
     wasi: preview2::WasiCtx,
     table: ResourceTable,
     _phantom: std::marker::PhantomData<R>,
@@ -257,6 +593,166 @@ where
     // }
 }
 
+impl<R, S> WasiHttpView for AsmlComponentFunctionState<R, S>
+where
+    R: RuntimeAbi<S> + Send + 'static,
+    S: Clone + Send + Sized + 'static,
+{
+    fn ctx(&mut self) -> &mut WasiHttpCtx {
+        &mut self.http
+    }
+
+    fn table(&mut self) -> &mut ResourceTable {
+        &mut self.table
+    }
+
+    fn send_request(
+        &mut self,
+        mut request: OutgoingRequest,
+    ) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+        let admitted = self.outbound_http.admit(&mut request);
+        outbound::send(self, admitted, request)
+    }
+}
+
+impl<R, S> AsmlComponentFunctionState<R, S>
+where
+    R: RuntimeAbi<S> + Send + 'static,
+    S: Clone + Send + Sized + 'static,
+{
+    /// Prepare a warm reactor instance for another invocation. The instance keeps its preopens,
+    /// environment & limits, but its `/tmp` is emptied so that nothing is carried over from the last
+    /// invocation; state which should persist belongs in the function's persistent cache. If `/tmp`
+    /// can't be emptied, the instance must not be reused.
+    pub fn begin_invocation(
+        &mut self,
+        status_tx: StatusTx<S>,
+        request_id: Option<String>,
+        input: Vec<u8>,
+        timeout: Option<Duration>,
+    ) -> std::io::Result<()> {
+        self.scratch_dir.clear()?;
+        if let Some((stdout, stderr)) = &self.captured_stdio {
+            stdout.set_request_id(request_id.clone());
+            stderr.set_request_id(request_id.clone());
+        }
+        self.status_sender = status_tx;
+        self.request_id = request_id;
+        self.function_input = input;
+        self.deadline = timeout.map(|timeout| Instant::now() + timeout);
+        self.response_stream = ResponseStream::NotStarted;
+        self.outbound_http.reset();
+        // Calls left behind by the previous invocation will never be polled
+        self.threader.lock().unwrap().cancel_all();
+        Ok(())
+    }
+
+    /// Start an IOmod call on the Threader, returning the call's IOID
+    fn invoke_iomod(
+        &mut self,
+        path: &str,
+        input: String,
+        timeout: Option<Duration>,
+    ) -> Result<asml_io::Ioid, asml_io::IoError> {
+        let mut threader = self.threader.lock().unwrap();
+        // The call fails like one the registry couldn't take, rather than stopping the guest
+        let ioid = threader.next_ioid().ok_or_else(|| {
+            asml_io::IoError::Iomod(asml_io::IomodError {
+                code: UNAVAILABLE.to_string(),
+                message: "unable to get a new IO ID".to_string(),
+                retryable: true,
+            })
+        })?;
+        threader.invoke(path, input.into_bytes(), ioid, timeout)?;
+        Ok(ioid as asml_io::Ioid)
+    }
+
+    /// Report `err` as the function's failure, and return it as the error which stops the guest
+    fn limit_exceeded(&self, err: LimitExceeded) -> anyhow::Error {
+        tracing::error!("{}", err);
+        R::failure(
+            self.status_sender.clone(),
+            self.failure_body(serde_json::json!({ "error": err.to_string() }).to_string().into_bytes()),
+            self.request_id.clone(),
+        );
+        err.into()
+    }
+
+    /// Log a crash report for an invocation which failed with `err`, and build the body of its failure
+    /// response. The report is included in the body if enabled by `ASML_RETURN_CRASH_REPORTS`.
+    pub fn crash_body(&self, err: &anyhow::Error, coordinates: Option<String>) -> Vec<u8> {
+        let report = CrashReport::new(err, coordinates, self.request_id.clone());
+        report.log();
+        self.failure_body(report.response_body(*RETURN_CRASH_REPORTS).into_bytes())
+    }
+
+    /// Add the guest's captured output to a failure response, if enabled by `ASML_RETURN_STDIO_ON_FAILURE`.
+    /// A JSON object body gains `stdout` & `stderr` fields; any other body is moved to an `error` field.
+    pub fn failure_body(&self, body: Vec<u8>) -> Vec<u8> {
+        let (stdout, stderr) = match (&self.captured_stdio, *RETURN_STDIO_ON_FAILURE) {
+            (Some(stdio), true) => stdio,
+            _ => return body,
+        };
+        let mut json = match serde_json::from_slice::<serde_json::Value>(&body) {
+            Ok(serde_json::Value::Object(map)) => map,
+            _ => {
+                let mut map = serde_json::Map::new();
+                map.insert("error".into(), String::from_utf8_lossy(&body).into());
+                map
+            }
+        };
+        json.insert("stdout".into(), stdout.tail().into());
+        json.insert("stderr".into(), stderr.tail().into());
+        serde_json::Value::Object(json).to_string().into_bytes()
+    }
+}
+
+impl<R, S> ResourceLimiter for AsmlComponentFunctionState<R, S>
+where
+    R: RuntimeAbi<S> + Send + 'static,
+    S: Clone + Send + Sized + 'static,
+{
+    fn memory_growing(
+        &mut self,
+        current: usize,
+        desired: usize,
+        _maximum: Option<usize>,
+    ) -> anyhow::Result<bool> {
+        // Memories only grow, so the total is kept up to date from each memory's growth
+        let total = self.memory_bytes.saturating_sub(current) + desired;
+        match self.memory_size_mb {
+            Some(limit_mb) if total > limit_mb as usize * 1024 * 1024 => {
+                Err(self.limit_exceeded(LimitExceeded::Memory { limit_mb }))
+            }
+            _ => {
+                self.memory_bytes = total;
+                Ok(true)
+            }
+        }
+    }
+
+    fn table_growing(
+        &mut self,
+        _current: u32,
+        desired: u32,
+        _maximum: Option<u32>,
+    ) -> anyhow::Result<bool> {
+        Ok(desired <= MAX_TABLE_ELEMENTS)
+    }
+
+    fn instances(&self) -> usize {
+        MAX_INSTANCES
+    }
+
+    fn tables(&self) -> usize {
+        MAX_TABLES
+    }
+
+    fn memories(&self) -> usize {
+        MAX_MEMORIES
+    }
+}
+
 impl<R, S> asml_io::Host for AsmlComponentFunctionState<R, S>
 where
     R: RuntimeAbi<S> + Send + 'static,
@@ -267,34 +763,49 @@ where
         path: String,
         input: String,
     ) -> anyhow::Result<Result<asml_io::Ioid, asml_io::IoError>> {
-        let ioid = self
-            .threader
-            .clone()
-            .lock()
-            .unwrap()
-            .next_ioid()
-            .expect("unable to get a new IO ID");
+        Ok(self.invoke_iomod(&path, input, None))
+    }
+
+    fn invoke_with_timeout(
+        &mut self,
+        path: String,
+        input: String,
+        timeout_ms: u32,
+    ) -> anyhow::Result<Result<asml_io::Ioid, asml_io::IoError>> {
+        Ok(self.invoke_iomod(&path, input, Some(Duration::from_millis(timeout_ms as u64))))
+    }
 
-        if let Err(err) = self.threader
+    fn poll(&mut self, ioid: asml_io::Ioid) -> anyhow::Result<Result<Vec<u8>, asml_io::PollError>> {
+        Ok(self.threader.clone().lock().unwrap().poll(ioid))
+    }
+
+    fn read(
+        &mut self,
+        ioid: asml_io::Ioid,
+        offset: u32,
+        len: u32,
+    ) -> anyhow::Result<Result<Vec<u8>, asml_io::PollError>> {
+        Ok(self
+            .threader
             .clone()
             .lock()
             .unwrap()
-            .invoke(&path, input.into_bytes(), ioid)
-        {
-            return Ok(Err(err));
-        }
+            .read(ioid, offset as usize, len as usize))
+    }
 
-        Ok(Ok(ioid as asml_io::Ioid))
+    fn release(&mut self, ioid: asml_io::Ioid) -> anyhow::Result<()> {
+        self.threader.clone().lock().unwrap().release(ioid);
+        Ok(())
     }
 
-    fn poll(&mut self, ioid: asml_io::Ioid) -> anyhow::Result<Result<Vec<u8>, asml_io::PollError>> {
-        match self.threader.clone().lock().unwrap().poll(ioid) {
-            Some(response) => Ok(Ok(response)),
-            None => Ok(Err(asml_io::PollError::NotReady)),
-        }
+    fn subscribe(&mut self, ioid: asml_io::Ioid) -> anyhow::Result<Resource<asml_io::Pollable>> {
+        let ready = self.threader.clone().lock().unwrap().subscribe(ioid);
+        let ready = self.table.push(ready)?;
+        preview2::subscribe(&mut self.table, ready)
     }
 }
 
+#[async_trait::async_trait]
 impl<R, S> asml_rt::Host for AsmlComponentFunctionState<R, S>
 where
     R: RuntimeAbi<S> + Send + 'static,
@@ -311,7 +822,7 @@ where
     fn failure(&mut self, response: Vec<u8>) -> anyhow::Result<()> {
         Ok(R::failure(
             self.status_sender.clone(),
-            response,
+            self.failure_body(response),
             self.request_id.clone(),
         ))
     }
@@ -336,6 +847,70 @@ where
     fn get_input(&mut self) -> anyhow::Result<Vec<u8>> {
         Ok(self.function_input.clone())
     }
+
+    // Host calls are made inside the span of the invocation's run phase
+    fn get_trace_id(&mut self) -> anyhow::Result<Option<String>> {
+        Ok(telemetry::trace_id(&tracing::Span::current()))
+    }
+
+    fn respond(&mut self, response: asml_rt::HttpResponse) -> anyhow::Result<()> {
+        Ok(R::respond(
+            self.status_sender.clone(),
+            response,
+            self.request_id.clone(),
+        ))
+    }
+
+    async fn begin_response(&mut self, status: u16, headers: Vec<(String, String)>) -> anyhow::Result<()> {
+        if !matches!(self.response_stream, ResponseStream::NotStarted) {
+            return Err(anyhow!("begin-response called after the response was started"));
+        }
+        self.response_stream = match R::STREAMING {
+            true => {
+                R::begin_response(self.status_sender.clone(), status, headers, self.request_id.clone())
+                    .await;
+                ResponseStream::Streaming
+            }
+            false => ResponseStream::Buffering(asml_rt::HttpResponse {
+                status,
+                headers,
+                body: Vec::new(),
+            }),
+        };
+        Ok(())
+    }
+
+    async fn write_chunk(&mut self, chunk: Vec<u8>) -> anyhow::Result<()> {
+        match &mut self.response_stream {
+            ResponseStream::Streaming => Ok(R::write_chunk(
+                self.status_sender.clone(),
+                chunk,
+                self.request_id.clone(),
+            )
+            .await),
+            ResponseStream::Buffering(response) => {
+                response.body.extend_from_slice(&chunk);
+                Ok(())
+            }
+            _ => Err(anyhow!("write-chunk called outside of a started response")),
+        }
+    }
+
+    async fn finish_response(&mut self) -> anyhow::Result<()> {
+        match std::mem::replace(&mut self.response_stream, ResponseStream::Finished) {
+            ResponseStream::Streaming => Ok(R::finish_response(
+                self.status_sender.clone(),
+                self.request_id.clone(),
+            )
+            .await),
+            ResponseStream::Buffering(response) => Ok(R::respond(
+                self.status_sender.clone(),
+                response,
+                self.request_id.clone(),
+            )),
+            _ => Err(anyhow!("finish-response called outside of a started response")),
+        }
+    }
 }
 
 impl<R, S> secret_storage::Host for AsmlComponentFunctionState<R, S>
@@ -376,15 +951,27 @@ where
         jwks: String,
         _params: jwt::decoder::ValidationParams,
     ) -> anyhow::Result<Result<jwt::decoder::VerifyResult, jwt::decoder::JwtError>> {
-        let mut cache = self.cache.lock().unwrap();
-        let key_set = match cache.get("jwt.keyset")? {
+        // Keysets are cached per JWKS URL, until the provider's keys are due to be refreshed
+        let cached = self.cache.lock().unwrap().get::<JwtKeyStore>(JWT_KEYSET_NAMESPACE, &jwks)?;
+        let key_set = match cached {
             Some(key_set) => key_set,
             None => {
-                let key_set = std::thread::spawn(move || JwtKeyStore::new_from_blocking(jwks.to_owned()).unwrap())
+                let url = jwks.clone();
+                let key_set = match std::thread::spawn(move || JwtKeyStore::new_from_blocking(url))
                     .join()
-                    .unwrap();
+                    .map_err(|_| anyhow!("JWKS download panicked"))?
+                {
+                    Ok(key_set) => key_set,
+                    Err(err) => {
+                        tracing::error!("could not load JWKS from {}: {}", &jwks, err.to_string());
+                        return Ok(Err(jwt::decoder::JwtError::InvalidJwks));
+                    }
+                };
 
-                cache.put("jwt.keyset", &key_set).unwrap();
+                let ttl = key_set
+                    .refresh_time()
+                    .map(|t| t.duration_since(SystemTime::now()).unwrap_or_default());
+                self.cache.lock().unwrap().put(JWT_KEYSET_NAMESPACE, &jwks, &key_set, ttl)?;
                 key_set
             },
         };
@@ -430,6 +1017,21 @@ where
     }
 }
 
+/// Return the target triple to precompile for, given an architecture name from `service.toml`
+pub fn precompile_target(arch: &str) -> Option<&'static str> {
+    match arch {
+        "x86_64" => Some("x86_64-linux-gnu"),
+        "aarch64" => Some("aarch64-linux-gnu"),
+        _ => None,
+    }
+}
+
+/// Path of the artifact precompiled from `wasm_path` for `target`, e.g. `handler.component.wasm.aarch64.bin`
+pub fn precompiled_path(wasm_path: &Path, target: &str) -> PathBuf {
+    let arch = target.split('-').next().unwrap_or(target);
+    PathBuf::from(format!("{}.{}.bin", wasm_path.display(), arch))
+}
+
 pub fn precompile(module_path: &Path, target: &str, mode: &str) -> anyhow::Result<Vec<u8>> {
     let file_path = format!("{}.bin", module_path.display().to_string());
     let is_component = file_path.contains(".component.");
@@ -451,6 +1053,12 @@ fn new_engine(target: Option<&str>, cpu_compat_mode: Option<&str>) -> anyhow::Re
         Some(mode) => mode,
         None => CPU_COMPAT_MODE.as_str(),
     };
+    // The compatibility modes disable x86 extensions, and don't apply to other architectures
+    let is_x86 = match target {
+        Some(target) => target.starts_with("x86_64"),
+        None => cfg!(target_arch = "x86_64"),
+    };
+    let mode = if is_x86 { mode } else { "default" };
     let mut config = match mode {
         "default" => Config::new().clone(),
         "high" => unsafe {
@@ -478,15 +1086,83 @@ fn new_engine(target: Option<&str>, cpu_compat_mode: Option<&str>) -> anyhow::Re
         None => config,
     };
     config.wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Enable);
+    config.epoch_interruption(true);
     config.wasm_component_model(true);
     config.wasm_multi_memory(true);
     config.async_support(true);
+    if let Some(instances) = *POOLING_INSTANCES {
+        let mut pooling = PoolingAllocationConfig::default();
+        pooling
+            .total_component_instances(instances)
+            .total_core_instances(instances * 16)
+            .total_memories(instances * 4)
+            .total_tables(instances * 4)
+            .memory_pages(POOLING_MEMORY_PAGES);
+        config.allocation_strategy(InstanceAllocationStrategy::Pooling(pooling));
+    }
     match Engine::new(&config) {
         Ok(engine) => Ok(engine),
         Err(err) => Err(anyhow!(err)),
     }
 }
 
+#[derive(Default)]
+struct EpochTickerState {
+    next_id: u64,
+    engines: HashMap<u64, Engine>,
+    running: bool,
+}
+
+// Engines whose epoch is incremented by the ticker thread
+static EPOCH_TICKER: Lazy<Mutex<EpochTickerState>> = Lazy::new(|| Mutex::new(Default::default()));
+
+/// Keeps an engine's epoch ticking until it is dropped.
+/// One thread ticks every engine in the process, and exits once none are left.
+struct EpochTick {
+    ticker: &'static Mutex<EpochTickerState>,
+    id: u64,
+}
+
+impl EpochTick {
+    fn new(engine: &Engine) -> Self {
+        Self::new_in(&EPOCH_TICKER, engine)
+    }
+
+    fn new_in(ticker: &'static Mutex<EpochTickerState>, engine: &Engine) -> Self {
+        let mut state = ticker.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.engines.insert(id, engine.clone());
+        if !state.running {
+            state.running = true;
+            std::thread::spawn(move || run_epoch_ticker(ticker));
+        }
+        Self { ticker, id }
+    }
+}
+
+impl Drop for EpochTick {
+    fn drop(&mut self) {
+        if let Ok(mut state) = self.ticker.lock() {
+            state.engines.remove(&self.id);
+        }
+    }
+}
+
+fn run_epoch_ticker(ticker: &'static Mutex<EpochTickerState>) {
+    loop {
+        std::thread::sleep(EPOCH_TICK);
+        let mut ticker = ticker.lock().unwrap();
+        if ticker.engines.is_empty() {
+            ticker.running = false;
+            return;
+        }
+        for engine in ticker.engines.values() {
+            engine.increment_epoch();
+        }
+    }
+}
+
 pub fn make_wasi_component(module: Vec<u8>, preview1: &[u8]) -> anyhow::Result<Vec<u8>> {
     println!("Encoding WASM Module as Component [{} bytes]...", module.len());
     let mut encoder = ComponentEncoder::default().validate(true).module(&module)?;
@@ -500,19 +1176,44 @@ pub fn make_wasi_component(module: Vec<u8>, preview1: &[u8]) -> anyhow::Result<V
     Ok(bytes)
 }
 
+/// Compose the component at `component_path` with the components in `dependencies`, whose exports satisfy
+/// its imports, into a single component. Imports not satisfied by a dependency (e.g. WASI, or the
+/// AssemblyLift host interfaces) remain imports of the composed component.
+pub fn compose_component(component_path: &Path, dependencies: &[PathBuf]) -> anyhow::Result<Vec<u8>> {
+    println!(
+        "Composing component {} with {} dependencies...",
+        component_path.display(),
+        dependencies.len()
+    );
+    let config = wasm_compose::config::Config {
+        definitions: dependencies.to_vec(),
+        ..Default::default()
+    };
+    wasm_compose::composer::ComponentComposer::new(component_path, &config)
+        .compose()
+        .context("failed to compose component")
+}
+
+// Packages the WIT depends on (i.e. its `deps`) must be pushed before it
 macro_rules! parse_wit {
-    ($path:expr) => {
+    (@push $resolve:ident, $path:expr) => {
         {
-            let mut resolve = Resolve::default();
-            let id = {
-                let contents = include_bytes!($path);
-                let text = match std::str::from_utf8(contents) {
-                    Ok(s) => s,
-                    Err(_) => anyhow::bail!("input file is not valid utf-8"),
-                };
-                let pkg = UnresolvedPackage::parse(&Path::new($path), text)?;
-                resolve.push(pkg)?
+            let contents = include_bytes!($path);
+            let text = match std::str::from_utf8(contents) {
+                Ok(s) => s,
+                Err(_) => anyhow::bail!("input file is not valid utf-8"),
             };
+            let pkg = UnresolvedPackage::parse(&Path::new($path), text)?;
+            $resolve.push(pkg)?
+        }
+    };
+    ($path:expr $(, $dep:expr)*) => {
+        {
+            let mut resolve = Resolve::default();
+            $(
+                parse_wit!(@push resolve, $dep);
+            )*
+            let id = parse_wit!(@push resolve, $path);
             anyhow::Ok::<(Resolve, PackageId)>((resolve, id))
         }
     };
@@ -541,7 +1242,10 @@ pub fn embed_asml_wit(module: Vec<u8>) -> anyhow::Result<Vec<u8>> {
     }
 
     let world = "assemblylift".to_string();
-    let (resolve, id) = parse_wit!("../../wit/assemblylift/assemblylift.wit")?;
+    let (resolve, id) = parse_wit!(
+        "../../wit/assemblylift/assemblylift.wit",
+        "../../wit/assemblylift/deps/io/poll.wit"
+    )?;
     let world = resolve.select_world(id, Some(&world))?;
     push_world(&mut wasm, world, resolve)?;
 
@@ -562,3 +1266,355 @@ pub fn embed_asml_wit(module: Vec<u8>) -> anyhow::Result<Vec<u8>> {
 
     Ok(wasm)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::sync::Mutex;
+    use std::time::{Duration, Instant};
+
+    use anyhow::anyhow;
+    use once_cell::sync::Lazy;
+    use tokio::sync::mpsc;
+    use uuid::Uuid;
+    use wasmtime::component::{Component, Linker};
+    use wasmtime::{Config, Engine, Store, Trap};
+
+    use crate::wasm::capabilities::{Capabilities, Preopen};
+    use crate::wasm::scratch::SCRATCH_ROOT;
+    use crate::wasm::{
+        asml_rt, compose_component, is_limit_exceeded, is_timeout, status_channel, Entrypoint, EpochTick,
+        EpochTickerState, FunctionLimits, FunctionStore, InvocationError, LimitExceeded, StatusRx, StatusTx,
+        Wasmtime, EPOCH_TICK,
+    };
+    use crate::{KeysAbi, RuntimeAbi, SecretsAbi};
+
+    #[derive(Clone, Debug)]
+    enum TestStatus {
+        Success,
+        Failure(Vec<u8>),
+        Respond,
+    }
+
+    struct TestAbi;
+
+    impl KeysAbi for TestAbi {
+        fn encrypt(_id: String, _plaintext: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+
+        fn decrypt(_id: String, _ciphertext: Vec<u8>) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow!("no keys"))
+        }
+    }
+
+    impl SecretsAbi for TestAbi {
+        fn get_secret(_id: String) -> anyhow::Result<Vec<u8>> {
+            Err(anyhow!("no secrets"))
+        }
+
+        fn set_secret(_id: String, _value: Vec<u8>, _key_id: Option<String>) -> anyhow::Result<()> {
+            Err(anyhow!("no secrets"))
+        }
+    }
+
+    impl RuntimeAbi<TestStatus> for TestAbi {
+        fn success(status_tx: StatusTx<TestStatus>, _response: Vec<u8>, _request_id: Option<String>) {
+            let _ = status_tx.try_send(TestStatus::Success);
+        }
+
+        fn failure(status_tx: StatusTx<TestStatus>, response: Vec<u8>, _request_id: Option<String>) {
+            let _ = status_tx.try_send(TestStatus::Failure(response));
+        }
+
+        fn respond(
+            status_tx: StatusTx<TestStatus>,
+            _response: asml_rt::HttpResponse,
+            _request_id: Option<String>,
+        ) {
+            let _ = status_tx.try_send(TestStatus::Respond);
+        }
+    }
+
+    /// Load a command component, whose `run` is the `run` export of the core module `module`
+    fn load_command(module: &str) -> Wasmtime<TestAbi, TestStatus> {
+        let wat = format!(
+            r#"
+            (component
+                (core module $m {})
+                (core instance $i (instantiate $m))
+                (func $run (result (result)) (canon lift (core func $i "run")))
+                (instance $cli (export "run" (func $run)))
+                (export "wasi:cli/run@0.2.0" (instance $cli)))
+            "#,
+            module
+        );
+        let dir = std::env::temp_dir().join(format!("asml-wasm-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("fn.component.wasm");
+        fs::write(&path, wat::parse_str(wat).unwrap()).unwrap();
+        let wasmtime = Wasmtime::<TestAbi, TestStatus>::new_from_path(&path).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        wasmtime
+    }
+
+    async fn link_command(
+        module: &str,
+        limits: FunctionLimits,
+    ) -> (
+        Wasmtime<TestAbi, TestStatus>,
+        Entrypoint,
+        FunctionStore<TestAbi, TestStatus>,
+        StatusRx<TestStatus>,
+    ) {
+        let wasmtime = load_command(module);
+        fs::create_dir_all(SCRATCH_ROOT.join("run")).unwrap();
+        let (status_tx, status_rx) = status_channel::<TestStatus>(1);
+        let (registry_tx, _) = mpsc::channel(1);
+        let (entrypoint, store) = wasmtime
+            .link_wasi_component(
+                registry_tx,
+                status_tx,
+                Vec::new(),
+                "default".to_string(),
+                Vec::new(),
+                &Capabilities::default(),
+                limits,
+                Default::default(),
+                None,
+                &[],
+            )
+            .await
+            .unwrap();
+        (wasmtime, entrypoint, store, status_rx)
+    }
+
+    async fn run_command(
+        wasmtime: &Wasmtime<TestAbi, TestStatus>,
+        entrypoint: Entrypoint,
+        store: &mut FunctionStore<TestAbi, TestStatus>,
+    ) -> anyhow::Result<()> {
+        match entrypoint {
+            Entrypoint::Command(command) => wasmtime.run_component(command, store).await,
+            Entrypoint::Reactor(_) => panic!("expected a command component"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_timeout() {
+        let module = r#"
+            (func (export "run") (result i32)
+                (loop $forever (br $forever))
+                i32.const 0)
+        "#;
+        let limits = FunctionLimits {
+            timeout: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let (wasmtime, entrypoint, mut store, _status_rx) = link_command(module, limits).await;
+
+        let started = Instant::now();
+        let err = run_command(&wasmtime, entrypoint, &mut store).await.unwrap_err();
+        assert!(is_timeout(&err), "expected a timeout, got {:?}", err);
+        assert!(matches!(err.downcast_ref::<Trap>(), Some(Trap::Interrupt)));
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert!(!is_limit_exceeded(&err));
+    }
+
+    #[test]
+    fn test_epoch_ticker_stops() {
+        // A ticker of its own, so that engines created by other tests don't keep it running
+        static TICKER: Lazy<Mutex<EpochTickerState>> = Lazy::new(|| Mutex::new(Default::default()));
+        let running = || TICKER.lock().unwrap().running;
+        let engine = Engine::default();
+
+        let first = EpochTick::new_in(&TICKER, &engine);
+        let second = EpochTick::new_in(&TICKER, &engine);
+        assert!(running());
+        drop(first);
+        std::thread::sleep(EPOCH_TICK * 5);
+        assert!(running(), "stopped while an engine was left");
+
+        drop(second);
+        let deadline = Instant::now() + Duration::from_secs(1);
+        while running() {
+            assert!(Instant::now() < deadline, "still running after the last engine was dropped");
+            std::thread::sleep(EPOCH_TICK);
+        }
+        assert!(TICKER.lock().unwrap().engines.is_empty());
+
+        // The next engine starts it again
+        let tick = EpochTick::new_in(&TICKER, &engine);
+        assert!(running());
+        drop(tick);
+    }
+
+    #[tokio::test]
+    async fn test_memory_limit() {
+        let module = r#"
+            (memory 1)
+            (func (export "run") (result i32)
+                ;; 32 more pages is past the 1 MB limit
+                (drop (memory.grow (i32.const 32)))
+                i32.const 0)
+        "#;
+        let limits = FunctionLimits {
+            memory_size_mb: Some(1),
+            ..Default::default()
+        };
+        let (wasmtime, entrypoint, mut store, status_rx) = link_command(module, limits).await;
+
+        let err = run_command(&wasmtime, entrypoint, &mut store).await.unwrap_err();
+        assert!(is_limit_exceeded(&err));
+        assert!(matches!(
+            err.downcast_ref::<LimitExceeded>(),
+            Some(LimitExceeded::Memory { limit_mb: 1 })
+        ));
+        // The failure is reported to the runtime
+        match status_rx.try_recv() {
+            Ok(TestStatus::Failure(body)) => {
+                assert!(String::from_utf8_lossy(&body).contains("memory limit exceeded"))
+            }
+            other => panic!("expected a failure, got {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_missing_bind_path() {
+        let wasmtime = load_command(r#"(func (export "run") (result i32) i32.const 0)"#);
+        fs::create_dir_all(SCRATCH_ROOT.join("run")).unwrap();
+        let missing = std::env::temp_dir().join(format!("asml-missing-{}", Uuid::new_v4()));
+        let link = |bind_paths: Vec<(String, String)>, capabilities: Capabilities| {
+            let (status_tx, _) = status_channel::<TestStatus>(1);
+            let (registry_tx, _) = mpsc::channel(1);
+            let wasmtime = &wasmtime;
+            async move {
+                wasmtime
+                    .link_wasi_component(
+                        registry_tx,
+                        status_tx,
+                        Vec::new(),
+                        "default".to_string(),
+                        bind_paths,
+                        &capabilities,
+                        FunctionLimits::default(),
+                        Default::default(),
+                        None,
+                        &[],
+                    )
+                    .await
+            }
+        };
+
+        let bind_paths = vec![(missing.to_string_lossy().to_string(), "/src".to_string())];
+        assert!(matches!(
+            link(bind_paths, Capabilities::default()).await,
+            Err(InvocationError::BadConfig(_))
+        ));
+
+        let mut capabilities = Capabilities::default();
+        capabilities.preopens.push(Preopen {
+            host_path: missing.to_string_lossy().to_string(),
+            guest_path: "/data".to_string(),
+            writable: false,
+        });
+        assert!(matches!(
+            link(Vec::new(), capabilities).await,
+            Err(InvocationError::BadConfig(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_memory_within_limit() {
+        let module = r#"
+            (memory 1)
+            (func (export "run") (result i32)
+                (drop (memory.grow (i32.const 8)))
+                i32.const 0)
+        "#;
+        let limits = FunctionLimits {
+            memory_size_mb: Some(1),
+            ..Default::default()
+        };
+        let (wasmtime, entrypoint, mut store, _) = link_command(module, limits).await;
+        assert!(run_command(&wasmtime, entrypoint, &mut store).await.is_ok());
+    }
+
+    // Exports `test:math/math`, which the app component imports
+    const MATH_COMPONENT: &str = r#"
+        (component
+            (core module $m
+                (func (export "double") (param i32) (result i32)
+                    local.get 0
+                    i32.const 2
+                    i32.mul))
+            (core instance $i (instantiate $m))
+            (func $double (param "x" u32) (result u32) (canon lift (core func $i "double")))
+            (instance $math (export "double" (func $double)))
+            (export "test:math/math" (instance $math)))
+    "#;
+
+    const APP_COMPONENT: &str = r#"
+        (component
+            (import "test:math/math" (instance $math
+                (export "double" (func (param "x" u32) (result u32)))))
+            (core func $double (canon lower (func $math "double")))
+            (core instance $imports (export "double" (func $double)))
+            (core module $m
+                (import "math" "double" (func $double (param i32) (result i32)))
+                (func (export "run") (result i32)
+                    i32.const 21
+                    call $double))
+            (core instance $i (instantiate $m (with "math" (instance $imports))))
+            (func (export "run") (result u32) (canon lift (core func $i "run"))))
+    "#;
+
+    #[test]
+    fn test_compose_component() {
+        let dir = std::env::temp_dir().join(format!("asml-compose-test-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("math.wat"), MATH_COMPONENT).unwrap();
+        fs::write(dir.join("app.wat"), APP_COMPONENT).unwrap();
+
+        let bytes = compose_component(&dir.join("app.wat"), &[dir.join("math.wat")]).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        // The composed component has no imports left, so it instantiates with an empty linker
+        let mut config = Config::new();
+        config.wasm_component_model(true);
+        let engine = Engine::new(&config).unwrap();
+        let component = Component::new(&engine, &bytes).unwrap();
+        let mut store = Store::new(&engine, ());
+        let instance = Linker::new(&engine).instantiate(&mut store, &component).unwrap();
+        let run = instance
+            .get_typed_func::<(), (u32,)>(&mut store, "run")
+            .unwrap();
+        assert_eq!((42,), run.call(&mut store, ()).unwrap());
+    }
+
+    #[test]
+    fn test_invocation_error_status_code() {
+        assert_eq!(400, InvocationError::BadRequest("missing header".into()).status_code());
+        assert_eq!(404, InvocationError::NotFound("/fn.wasm".into()).status_code());
+        assert_eq!(503, InvocationError::Unavailable("no scratch root".into()).status_code());
+        // Configuration is deployed with the function, so isn't the caller's fault
+        assert_eq!(500, InvocationError::BadConfig("invalid capabilities".into()).status_code());
+        assert_eq!(500, InvocationError::Failed(anyhow!("trap")).status_code());
+    }
+
+    #[test]
+    fn test_invocation_error_display() {
+        assert_eq!(
+            "bad request: missing header",
+            InvocationError::BadRequest("missing header".into()).to_string()
+        );
+        assert_eq!(
+            "invalid function configuration: ASML_FUNCTION_IOMODS: expected value",
+            InvocationError::BadConfig("ASML_FUNCTION_IOMODS: expected value".into()).to_string()
+        );
+        // The whole chain of a failure is shown
+        let err: InvocationError = anyhow!("not a component").context("could not load").into();
+        assert!(matches!(err, InvocationError::Failed(_)));
+        assert_eq!("could not start function: could not load: not a component", err.to_string());
+    }
+}
@@ -0,0 +1,228 @@
+//! Outbound HTTP requests made by guests through `wasi:http/outgoing-handler`.
+//!
+//! Each request is checked against the function's `http` capability before it is sent; a request
+//! which isn't allowed fails with `HTTP-request-denied` without leaving the host.
+
+use std::time::Duration;
+
+use wasmtime::component::Resource;
+use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+use wasmtime_wasi_http::types::{default_send_request, HostFutureIncomingResponse, OutgoingRequest};
+use wasmtime_wasi_http::WasiHttpView;
+
+use crate::wasm::capabilities::HttpCapability;
+
+/// The outbound HTTP policy of a function instance, and the requests it has made this invocation
+pub struct OutboundHttp {
+    capability: Option<HttpCapability>,
+    requests: u32,
+}
+
+impl OutboundHttp {
+    pub fn new(capability: Option<HttpCapability>) -> Self {
+        Self {
+            capability,
+            requests: 0,
+        }
+    }
+
+    /// Start counting requests for a new invocation
+    pub fn reset(&mut self) {
+        self.requests = 0;
+    }
+
+    /// Check `request` against the capability, counting it and applying the capability's timeouts
+    /// if it is allowed. Returns why the request was denied, otherwise.
+    pub fn admit(&mut self, request: &mut OutgoingRequest) -> Result<(), String> {
+        let http = match &self.capability {
+            Some(http) => http,
+            None => return Err("outbound http is not enabled for this function".to_string()),
+        };
+        let scheme = if request.use_tls { "https" } else { "http" };
+        let method = request.request.method().to_string();
+        if !http.allows(scheme, &request.authority, &method) {
+            return Err(format!("{} {}://{} is not allowed", method, scheme, request.authority));
+        }
+        if http.max_requests.map_or(false, |max| self.requests >= max) {
+            return Err("request limit reached".to_string());
+        }
+        self.requests += 1;
+
+        if let Some(timeout_ms) = http.timeout_ms {
+            let timeout = Duration::from_millis(timeout_ms);
+            request.connect_timeout = request.connect_timeout.min(timeout);
+            request.first_byte_timeout = request.first_byte_timeout.min(timeout);
+            request.between_bytes_timeout = request.between_bytes_timeout.min(timeout);
+        }
+        Ok(())
+    }
+}
+
+/// Send `request` if it was admitted, or else fail it with `HTTP-request-denied`
+pub fn send(
+    view: &mut dyn WasiHttpView,
+    admitted: Result<(), String>,
+    request: OutgoingRequest,
+) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+    match admitted {
+        Ok(()) => Ok(default_send_request(view, request)?),
+        Err(reason) => {
+            tracing::warn!("denied outbound http request: {}", reason);
+            let response = HostFutureIncomingResponse::ready(Ok(Err(ErrorCode::HttpRequestDenied)));
+            Ok(view.table().push(response)?)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use http_body_util::{BodyExt, Empty};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use wasmtime::component::{Resource, ResourceTable};
+    use wasmtime_wasi_http::bindings::http::types::ErrorCode;
+    use wasmtime_wasi_http::types::{HostFutureIncomingResponse, OutgoingRequest};
+    use wasmtime_wasi_http::{WasiHttpCtx, WasiHttpView};
+
+    use super::{send, OutboundHttp};
+    use crate::wasm::capabilities::HttpCapability;
+
+    struct TestView {
+        table: ResourceTable,
+        http: WasiHttpCtx,
+        outbound: OutboundHttp,
+    }
+
+    impl WasiHttpView for TestView {
+        fn ctx(&mut self) -> &mut WasiHttpCtx {
+            &mut self.http
+        }
+
+        fn table(&mut self) -> &mut ResourceTable {
+            &mut self.table
+        }
+
+        fn send_request(
+            &mut self,
+            mut request: OutgoingRequest,
+        ) -> wasmtime::Result<Resource<HostFutureIncomingResponse>> {
+            let admitted = self.outbound.admit(&mut request);
+            send(self, admitted, request)
+        }
+    }
+
+    fn test_view(capability: HttpCapability) -> TestView {
+        TestView {
+            table: ResourceTable::new(),
+            http: WasiHttpCtx,
+            outbound: OutboundHttp::new(Some(capability)),
+        }
+    }
+
+    fn get(authority: &str) -> OutgoingRequest {
+        let body = Empty::new().map_err(|never| match never {}).boxed();
+        OutgoingRequest {
+            use_tls: false,
+            authority: authority.to_string(),
+            request: http::Request::builder()
+                .method("GET")
+                .uri(format!("http://{}/hello", authority))
+                .body(body)
+                .unwrap(),
+            connect_timeout: Duration::from_secs(5),
+            first_byte_timeout: Duration::from_secs(5),
+            between_bytes_timeout: Duration::from_secs(5),
+        }
+    }
+
+    /// Wait for the response to a request sent through `view`, returning its status code
+    async fn status(
+        view: &mut TestView,
+        request: OutgoingRequest,
+    ) -> Result<u16, ErrorCode> {
+        let resource = view.send_request(request).unwrap();
+        let response = match view.table().delete(resource).unwrap() {
+            HostFutureIncomingResponse::Pending(handle) => handle.await,
+            HostFutureIncomingResponse::Ready(response) => response,
+            HostFutureIncomingResponse::Consumed => panic!("response already consumed"),
+        };
+        response.unwrap().map(|response| response.resp.status().as_u16())
+    }
+
+    /// A stub server which answers each request with `204 No Content`, sending the request lines it receives
+    async fn stub_server() -> (u16, tokio::sync::mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                let mut buf = vec![0u8; 4096];
+                let n = stream.read(&mut buf).await.unwrap();
+                let request = String::from_utf8_lossy(&buf[..n]).to_string();
+                let _ = tx.send(request.lines().next().unwrap_or_default().to_string());
+                stream
+                    .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\nconnection: close\r\n\r\n")
+                    .await
+                    .unwrap();
+            }
+        });
+        (port, rx)
+    }
+
+    #[tokio::test]
+    async fn test_allowed_request() {
+        let (port, mut requests) = stub_server().await;
+        let mut view = test_view(HttpCapability {
+            hosts: vec![format!("127.0.0.1:{}", port)],
+            schemes: vec!["http".to_string()],
+            max_requests: Some(1),
+            ..Default::default()
+        });
+
+        let authority = format!("127.0.0.1:{}", port);
+        assert!(matches!(status(&mut view, get(&authority)).await, Ok(204)));
+        assert_eq!("GET /hello HTTP/1.1", requests.recv().await.unwrap());
+
+        // The request limit is reached, so this request never leaves the host
+        assert!(matches!(
+            status(&mut view, get(&authority)).await,
+            Err(ErrorCode::HttpRequestDenied)
+        ));
+        view.outbound.reset();
+        assert!(matches!(status(&mut view, get(&authority)).await, Ok(204)));
+    }
+
+    #[tokio::test]
+    async fn test_denied_request() {
+        let (port, mut requests) = stub_server().await;
+        let mut view = test_view(HttpCapability {
+            hosts: vec![format!("127.0.0.1:{}", port)],
+            schemes: vec!["http".to_string()],
+            ..Default::default()
+        });
+
+        // The host is allowed on another port only
+        let other_port = if port == u16::MAX { port - 1 } else { port + 1 };
+        assert!(matches!(
+            status(&mut view, get(&format!("127.0.0.1:{}", other_port))).await,
+            Err(ErrorCode::HttpRequestDenied)
+        ));
+        assert!(matches!(
+            status(&mut view, get(&format!("localhost:{}", port))).await,
+            Err(ErrorCode::HttpRequestDenied)
+        ));
+        assert!(requests.try_recv().is_err());
+
+        let mut view = TestView {
+            table: ResourceTable::new(),
+            http: WasiHttpCtx,
+            outbound: OutboundHttp::new(None),
+        };
+        assert!(matches!(
+            status(&mut view, get(&format!("127.0.0.1:{}", port))).await,
+            Err(ErrorCode::HttpRequestDenied)
+        ));
+    }
+}
@@ -0,0 +1,195 @@
+//! Per-invocation scratch directories, preopened as `/tmp` inside the guest.
+//!
+//! Each invocation gets its own directory under `$ASML_SCRATCH_DIR/run`, which is removed
+//! when the invocation's store is dropped. Functions may opt into a persistent cache directory
+//! under `$ASML_SCRATCH_DIR/cache`, which survives across invocations of the same function.
+//!
+//! The quota on a scratch directory is best-effort: the directory's size is checked periodically
+//! while the guest runs, so a guest can write past its quota until the next check. For a hard
+//! limit on disk usage, put `$ASML_SCRATCH_DIR` on a size-limited volume.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use once_cell::sync::Lazy;
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+use super::LimitExceeded;
+
+/// Quota of a function's scratch directory when its deployment doesn't set one
+pub const DEFAULT_TMP_QUOTA_MB: u32 = 512;
+
+pub static SCRATCH_ROOT: Lazy<PathBuf> = Lazy::new(|| {
+    PathBuf::from(std::env::var("ASML_SCRATCH_DIR").unwrap_or("/tmp/asmltmp".to_string()))
+});
+
+/// Create the scratch root, removing any invocation directories left behind by a previous process
+pub fn init_root() -> io::Result<()> {
+    let run_dir = SCRATCH_ROOT.join("run");
+    if run_dir.exists() {
+        fs::remove_dir_all(&run_dir)?;
+    }
+    fs::create_dir_all(&run_dir)?;
+    fs::create_dir_all(SCRATCH_ROOT.join("cache"))
+}
+
+/// Return the persistent cache directory for the function identified by `function_key`,
+/// creating it if it doesn't exist
+pub fn function_cache_dir(function_key: &str) -> io::Result<PathBuf> {
+    let dir = SCRATCH_ROOT.join("cache").join(cache_dir_name(function_key));
+    fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+// Percent-encode the characters which could make distinct keys share a directory, or leave the cache root
+fn cache_dir_name(function_key: &str) -> String {
+    let mut name = String::with_capacity(function_key.len());
+    for c in function_key.chars() {
+        match c {
+            '%' | '/' | '\\' | '.' => name.push_str(&format!("%{:02X}", c as u32)),
+            c => name.push(c),
+        }
+    }
+    name
+}
+
+pub struct ScratchDir {
+    path: PathBuf,
+    quota_mb: Option<u32>,
+}
+
+impl ScratchDir {
+    pub fn new(quota_mb: Option<u32>) -> io::Result<Self> {
+        Self::new_in(&SCRATCH_ROOT.join("run"), quota_mb)
+    }
+
+    fn new_in(run_dir: &Path, quota_mb: Option<u32>) -> io::Result<Self> {
+        let path = run_dir.join(Uuid::new_v4().to_string());
+        fs::create_dir_all(&path)?;
+        debug!("created scratch directory {}", path.display());
+        Ok(Self { path, quota_mb })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Remove everything in the directory, leaving it empty for the next invocation of a warm instance
+    pub fn clear(&self) -> io::Result<()> {
+        for entry in fs::read_dir(&self.path)? {
+            let entry = entry?;
+            match entry.file_type()?.is_dir() {
+                true => fs::remove_dir_all(entry.path())?,
+                false => fs::remove_file(entry.path())?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Check the total size of the directory against its quota, if it has one
+    pub fn check_quota(&self) -> Result<(), LimitExceeded> {
+        match self.quota_mb {
+            Some(limit_mb) if dir_size(&self.path) > limit_mb as u64 * 1024 * 1024 => {
+                Err(LimitExceeded::TmpQuota { limit_mb })
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Err(err) = fs::remove_dir_all(&self.path) {
+            warn!(
+                "could not remove scratch directory {}: {}",
+                self.path.display(),
+                err.to_string()
+            );
+        }
+    }
+}
+
+fn dir_size(path: &Path) -> u64 {
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(_) => return 0,
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| match entry.file_type() {
+            Ok(t) if t.is_dir() => dir_size(&entry.path()),
+            Ok(_) => entry.metadata().map(|m| m.len()).unwrap_or(0),
+            Err(_) => 0,
+        })
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use uuid::Uuid;
+
+    use super::{cache_dir_name, ScratchDir};
+    use crate::wasm::LimitExceeded;
+
+    #[test]
+    fn test_cache_dir_name() {
+        assert_eq!("%2Fopt%2Ffn%2Ecomponent%2Ewasm", cache_dir_name("/opt/fn.component.wasm"));
+        // Keys which differ only in the characters that are encoded don't collide
+        assert_ne!(cache_dir_name("a/b_c"), cache_dir_name("a_b/c"));
+        assert_ne!(cache_dir_name("a/b"), cache_dir_name("a%2Fb"));
+        assert_eq!("%2E%2E", cache_dir_name(".."));
+    }
+
+    #[test]
+    fn test_quota() {
+        let run_dir = std::env::temp_dir().join(format!("asml-scratch-test-{}", Uuid::new_v4()));
+        let scratch = ScratchDir::new_in(&run_dir, Some(1)).unwrap();
+        assert!(scratch.check_quota().is_ok());
+
+        // Files in subdirectories count towards the quota
+        fs::create_dir(scratch.path().join("sub")).unwrap();
+        fs::write(scratch.path().join("a"), vec![0u8; 512 * 1024]).unwrap();
+        fs::write(scratch.path().join("sub/b"), vec![0u8; 512 * 1024]).unwrap();
+        assert!(scratch.check_quota().is_ok());
+
+        fs::write(scratch.path().join("sub/c"), vec![0u8; 1]).unwrap();
+        assert!(matches!(
+            scratch.check_quota(),
+            Err(LimitExceeded::TmpQuota { limit_mb: 1 })
+        ));
+
+        let path = scratch.path().to_path_buf();
+        drop(scratch);
+        assert!(!path.exists());
+        fs::remove_dir_all(&run_dir).unwrap();
+    }
+
+    #[test]
+    fn test_clear() {
+        let run_dir = std::env::temp_dir().join(format!("asml-scratch-test-{}", Uuid::new_v4()));
+        let scratch = ScratchDir::new_in(&run_dir, None).unwrap();
+        fs::create_dir(scratch.path().join("sub")).unwrap();
+        fs::write(scratch.path().join("a"), b"a").unwrap();
+        fs::write(scratch.path().join("sub/b"), b"b").unwrap();
+
+        scratch.clear().unwrap();
+        assert!(scratch.path().exists());
+        assert_eq!(0, fs::read_dir(scratch.path()).unwrap().count());
+        drop(scratch);
+        fs::remove_dir_all(&run_dir).unwrap();
+    }
+
+    #[test]
+    fn test_no_quota() {
+        let run_dir = std::env::temp_dir().join(format!("asml-scratch-test-{}", Uuid::new_v4()));
+        let scratch = ScratchDir::new_in(&run_dir, None).unwrap();
+        fs::write(scratch.path().join("a"), vec![0u8; 2 * 1024 * 1024]).unwrap();
+        assert!(scratch.check_quota().is_ok());
+        drop(scratch);
+        fs::remove_dir_all(&run_dir).unwrap();
+    }
+}
@@ -0,0 +1,299 @@
+//! Per-invocation capture of guest stdout & stderr.
+//!
+//! Each line written by the guest is emitted as a `tracing` event tagged with the invocation's
+//! request id, so that output from concurrent invocations can be told apart. The last few KB of
+//! each stream are kept so that they can be returned with a failure. A line longer than
+//! `MAX_LINE` bytes is emitted in parts, so that a guest can't make the host buffer without limit.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use once_cell::sync::Lazy;
+use wasmtime_wasi::preview2::{HostOutputStream, StdoutStream, StreamResult, Subscribe};
+
+/// When true, captured output is added to the body of a failed invocation
+pub static RETURN_STDIO_ON_FAILURE: Lazy<bool> = Lazy::new(|| {
+    std::env::var("ASML_RETURN_STDIO_ON_FAILURE")
+        .map(|v| v.eq_ignore_ascii_case("true"))
+        .unwrap_or(false)
+});
+
+// Bytes of output kept per stream to return with a failure
+const TAIL_CAPACITY: usize = 16 * 1024;
+
+// Bytes of a line buffered before it is emitted without waiting for its newline
+const MAX_LINE: usize = TAIL_CAPACITY;
+
+#[derive(Clone, Copy, Debug)]
+pub enum Stream {
+    Stdout,
+    Stderr,
+}
+
+impl Stream {
+    fn name(&self) -> &'static str {
+        match self {
+            Stream::Stdout => "stdout",
+            Stream::Stderr => "stderr",
+        }
+    }
+}
+
+/// One of the guest's output streams. Clones share the same buffers.
+#[derive(Clone)]
+pub struct CapturedOutput {
+    inner: Arc<Mutex<Output>>,
+}
+
+struct Output {
+    stream: Stream,
+    request_id: Option<String>,
+    line: Vec<u8>,
+    tail: VecDeque<u8>,
+}
+
+impl CapturedOutput {
+    pub fn new(stream: Stream, request_id: Option<String>) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(Output {
+                stream,
+                request_id,
+                line: Vec::new(),
+                tail: VecDeque::with_capacity(TAIL_CAPACITY),
+            })),
+        }
+    }
+
+    /// Tag subsequent lines with `request_id`, e.g. when a warm instance is reused.
+    /// The tail is cleared, since it belongs to the previous invocation.
+    pub fn set_request_id(&self, request_id: Option<String>) {
+        let mut inner = self.inner.lock().unwrap();
+        if !inner.line.is_empty() {
+            inner.emit_line();
+        }
+        inner.request_id = request_id;
+        inner.tail.clear();
+    }
+
+    /// The most recent output written to the stream, up to `TAIL_CAPACITY` bytes
+    pub fn tail(&self) -> String {
+        let inner = self.inner.lock().unwrap();
+        let (front, back) = inner.tail.as_slices();
+        String::from_utf8_lossy(&[front, back].concat()).to_string()
+    }
+}
+
+impl Output {
+    fn write(&mut self, bytes: &[u8]) {
+        for b in bytes {
+            if self.tail.len() == TAIL_CAPACITY {
+                self.tail.pop_front();
+            }
+            self.tail.push_back(*b);
+
+            match *b {
+                b'\n' => self.emit_line(),
+                _ => {
+                    self.line.push(*b);
+                    if self.line.len() == MAX_LINE {
+                        self.emit_line();
+                    }
+                }
+            }
+        }
+    }
+
+    fn emit_line(&mut self) {
+        let line = String::from_utf8_lossy(&self.line);
+        let request_id = self.request_id.as_deref().unwrap_or("-");
+        match self.stream {
+            Stream::Stdout => {
+                tracing::info!(target: "guest", request_id, stream = self.stream.name(), "{}", line)
+            }
+            Stream::Stderr => {
+                tracing::warn!(target: "guest", request_id, stream = self.stream.name(), "{}", line)
+            }
+        }
+        self.line.clear();
+    }
+}
+
+impl Drop for Output {
+    fn drop(&mut self) {
+        if !self.line.is_empty() {
+            self.emit_line();
+        }
+    }
+}
+
+impl StdoutStream for CapturedOutput {
+    fn stream(&self) -> Box<dyn HostOutputStream> {
+        Box::new(self.clone())
+    }
+
+    fn isatty(&self) -> bool {
+        false
+    }
+}
+
+impl HostOutputStream for CapturedOutput {
+    fn write(&mut self, bytes: Bytes) -> StreamResult<()> {
+        self.inner.lock().unwrap().write(&bytes);
+        Ok(())
+    }
+
+    fn flush(&mut self) -> StreamResult<()> {
+        Ok(())
+    }
+
+    fn check_write(&mut self) -> StreamResult<usize> {
+        Ok(usize::MAX)
+    }
+}
+
+#[async_trait::async_trait]
+impl Subscribe for CapturedOutput {
+    async fn ready(&mut self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fmt;
+    use std::sync::{Arc, Mutex};
+
+    use tracing::field::{Field, Visit};
+    use tracing::{Event, Subscriber};
+    use tracing_subscriber::layer::{Context, SubscriberExt};
+    use tracing_subscriber::Layer;
+
+    use super::{CapturedOutput, Output, Stream, MAX_LINE, TAIL_CAPACITY};
+
+    /// Keeps the (request id, line) of each line emitted under the `guest` target
+    #[derive(Clone, Default)]
+    struct Lines(Arc<Mutex<Vec<(String, String)>>>);
+
+    #[derive(Default)]
+    struct LineVisitor {
+        request_id: String,
+        message: String,
+    }
+
+    impl Visit for LineVisitor {
+        fn record_str(&mut self, field: &Field, value: &str) {
+            if field.name() == "request_id" {
+                self.request_id = value.to_string();
+            }
+        }
+
+        fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+            if field.name() == "message" {
+                self.message = format!("{:?}", value);
+            }
+        }
+    }
+
+    impl<S: Subscriber> Layer<S> for Lines {
+        fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+            if event.metadata().target() == "guest" {
+                let mut visitor = LineVisitor::default();
+                event.record(&mut visitor);
+                self.0.lock().unwrap().push((visitor.request_id, visitor.message));
+            }
+        }
+    }
+
+    /// Run `f`, returning the lines it emits
+    fn capture(f: impl FnOnce()) -> Vec<(String, String)> {
+        let lines = Lines::default();
+        let subscriber = tracing_subscriber::registry().with(lines.clone());
+        tracing::subscriber::with_default(subscriber, f);
+        let lines = lines.0.lock().unwrap().clone();
+        lines
+    }
+
+    fn write(output: &CapturedOutput, bytes: &[u8]) {
+        output.inner.lock().unwrap().write(bytes);
+    }
+
+    #[test]
+    fn test_lines() {
+        let lines = capture(|| {
+            let output = CapturedOutput::new(Stream::Stdout, Some("req-1".into()));
+            write(&output, b"first\nsec");
+            write(&output, b"ond\n\nthird");
+        });
+        assert_eq!(
+            vec![
+                ("req-1".to_string(), "first".to_string()),
+                ("req-1".to_string(), "second".to_string()),
+                ("req-1".to_string(), "".to_string()),
+                // Flushed once the stream is dropped
+                ("req-1".to_string(), "third".to_string()),
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn test_long_line() {
+        let lines = capture(|| {
+            let output = CapturedOutput::new(Stream::Stderr, None);
+            write(&output, &vec![b'x'; MAX_LINE + 10]);
+            // Nothing past the limit is held back
+            assert_eq!(10, output.inner.lock().unwrap().line.len());
+        });
+        assert_eq!(2, lines.len());
+        assert_eq!(("-".to_string(), "x".repeat(MAX_LINE)), lines[0]);
+        assert_eq!("x".repeat(10), lines[1].1);
+    }
+
+    #[test]
+    fn test_set_request_id() {
+        let lines = capture(|| {
+            let output = CapturedOutput::new(Stream::Stdout, Some("req-1".into()));
+            write(&output, b"partial");
+            output.set_request_id(Some("req-2".into()));
+            assert_eq!("", output.tail());
+            write(&output, b"next\n");
+            assert_eq!("next\n", output.tail());
+        });
+        assert_eq!(
+            vec![
+                ("req-1".to_string(), "partial".to_string()),
+                ("req-2".to_string(), "next".to_string()),
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn test_tail() {
+        let output = CapturedOutput::new(Stream::Stdout, None);
+        write(&output, b"hello\n");
+        assert_eq!("hello\n", output.tail());
+
+        // Only the most recent output is kept
+        let mut bytes = vec![b'a'; TAIL_CAPACITY];
+        bytes.extend_from_slice(b"end\n");
+        write(&output, &bytes);
+        let tail = output.tail();
+        assert_eq!(TAIL_CAPACITY, tail.len());
+        assert!(tail.ends_with("aaend\n"));
+        assert!(!tail.contains("hello"));
+    }
+
+    #[test]
+    fn test_drop_flushes_line() {
+        let lines = capture(|| {
+            let mut output = Output {
+                stream: Stream::Stdout,
+                request_id: Some("req-1".into()),
+                line: Vec::new(),
+                tail: Default::default(),
+            };
+            output.write(b"no newline");
+        });
+        assert_eq!(vec![("req-1".to_string(), "no newline".to_string())], lines);
+    }
+}
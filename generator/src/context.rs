@@ -2,7 +2,9 @@ use std::path::PathBuf;
 use std::rc::Rc;
 
 use anyhow::anyhow;
+use assemblylift_core::runtime_environment;
 use handlebars::Handlebars;
+use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
 use super::projectfs::Project as ProjectFs;
@@ -168,6 +170,15 @@ impl Context {
                 };
                 // FIXME language should not be optional at context level
                 let language = function.language.clone().unwrap_or("rust".to_string());
+                let runtime_environment = match runtime_environment::by_language(&language) {
+                    Some(environment) => environment,
+                    None => {
+                        return Err(format!(
+                            "unsupported language `{}` for function `{}`",
+                            &language, &function.name
+                        ))
+                    }
+                };
                 let environment_variables = function
                     .environment
                     .clone()
@@ -192,13 +203,13 @@ impl Context {
                     //     _ => "handler.wasm.bin".into(),
                     // },
                     handler_name: format!("{}.component.{}", function.name.clone(), ext),
-                    // TODO this should not be inferred at context level
-                    runtime_environment: match language.as_str() {
-                        "rust" => "native",
-                        "ruby" => "ruby",
-                        _ => return Err("default".into()),
-                    }
-                    .into(),
+                    runtime_environment: runtime_environment.name.into(),
+                    interpreter: runtime_environment.interpreter.as_ref().map(|interpreter| Interpreter {
+                        name: runtime_environment.name.into(),
+                        version: interpreter.version.into(),
+                        assets: interpreter.assets.into(),
+                        source_dir: interpreter.source_dir.into(),
+                    }),
                     // FIXME don't hardcode
                     runtime_version: "0.4.0-beta.0".into(),
                     size: function.size_mb.unwrap_or(1024u16),
@@ -208,6 +219,31 @@ impl Context {
                         .clone()
                         .unwrap_or("default".to_string()),
                     precompiled: precompile,
+                    architectures: {
+                        let architectures = function
+                            .architectures
+                            .clone()
+                            .unwrap_or(vec!["x86_64".to_string()]);
+                        if let Some(arch) = architectures
+                            .iter()
+                            .find(|&a| a != "x86_64" && a != "aarch64")
+                        {
+                            return Err(format!(
+                                "unsupported architecture `{}` for function `{}`; must be one of `x86_64`, `aarch64`",
+                                arch, &function.name
+                            ));
+                        }
+                        architectures
+                    },
+                    components: function.components.clone().unwrap_or_default(),
+                    tmp_quota_mb: function.tmp_quota_mb,
+                    iomod_timeout_ms: function.iomod_timeout_ms,
+                    iomods: iomods_literal(&iomods)?,
+                    persistent_cache: function.persistent_cache.unwrap_or(false),
+                    capabilities: match &function.capabilities {
+                        Some(caps) => Some(capabilities_literal(caps)?),
+                        None => None,
+                    },
                     http: match &function.clone().http.as_ref() {
                         Some(http) => Some(Http {
                             verb: http.verb.clone(),
@@ -314,7 +350,11 @@ impl Context {
                     None => None,
                 },
                 is_root: service_ref.is_root,
-                has_ruby: ctx_functions.clone().iter().find(|&f| f.language.eq("ruby")).is_some(),
+                interpreters: ctx_functions
+                    .iter()
+                    .filter_map(|f| f.interpreter.clone())
+                    .unique_by(|i| i.name.clone())
+                    .collect(),
             });
 
             for iomod in iomods {
@@ -582,7 +622,8 @@ pub struct Service {
     pub container_registry: Option<Registry>,
     pub domain: Option<Domain>,
     pub is_root: Option<bool>,
-    pub has_ruby: bool,
+    /// Interpreters used by the service's functions
+    pub interpreters: Vec<Interpreter>,
 }
 
 impl Service {
@@ -621,7 +662,7 @@ impl From<&Service> for Service {
                 None => None,
             },
             is_root: value.is_root,
-            has_ruby: value.has_ruby,
+            interpreters: value.interpreters.clone(),
         }
     }
 }
@@ -635,6 +676,7 @@ pub struct Function {
     pub language: String,
     pub handler_name: String,
     pub runtime_environment: String,
+    pub interpreter: Option<Interpreter>,
     pub runtime_version: String,
     pub environment_variables: StringMap<String>,
     pub http: Option<Http>,
@@ -643,6 +685,18 @@ pub struct Function {
     pub timeout: u16,
     pub cpu_compat_mode: String,
     pub precompiled: bool,
+    /// Architectures to precompile for; the first is the default
+    pub architectures: Vec<String>,
+    /// Components composed into the function's component, relative to the function's directory
+    pub components: Vec<String>,
+    pub tmp_quota_mb: Option<u32>,
+    /// Default timeout for the function's IOmod calls
+    pub iomod_timeout_ms: Option<u32>,
+    /// The service's IOmod dependencies as a quoted JSON string, in the format read from `ASML_FUNCTION_IOMODS`
+    pub iomods: Option<String>,
+    pub persistent_cache: bool,
+    /// Capabilities as a quoted JSON string, which is also a valid HCL & Dockerfile string literal
+    pub capabilities: Option<String>,
 }
 
 impl Function {
@@ -651,6 +705,71 @@ impl Function {
     }
 }
 
+/// Encode a service's IOmod dependencies as a JSON object of coordinates to version requirements
+fn iomods_literal(iomods: &toml::service::Iomods) -> Result<Option<String>, String> {
+    if iomods.is_empty() {
+        return Ok(None);
+    }
+    let json: serde_json::Map<String, serde_json::Value> = iomods
+        .iter()
+        .map(|d| (d.coordinates.clone(), d.version.clone().into()))
+        .collect();
+    serde_json::to_string(&serde_json::Value::Object(json).to_string())
+        .map(Some)
+        .map_err(|e| e.to_string())
+}
+
+/// Encode a function's capabilities in the format read by the runtime from `ASML_FUNCTION_CAPABILITIES`
+fn capabilities_literal(caps: &toml::service::Capabilities) -> Result<String, String> {
+    let stdio = caps.stdio.clone().unwrap_or("capture".to_string());
+    if !["capture", "inherit", "null"].contains(&stdio.as_str()) {
+        return Err(format!(
+            "invalid stdio capability `{}`; must be one of `capture`, `inherit`, `null`",
+            stdio
+        ));
+    }
+    let preopens = caps
+        .preopens
+        .clone()
+        .unwrap_or_default()
+        .iter()
+        .map(|p| {
+            serde_json::json!({
+                "host_path": p.host_path,
+                "guest_path": p.guest_path,
+                "writable": p.writable.unwrap_or(false),
+            })
+        })
+        .collect::<Vec<serde_json::Value>>();
+    let http = caps.http.as_ref().map(|http| {
+        serde_json::json!({
+            "hosts": http.hosts.clone().unwrap_or_default(),
+            "methods": http.methods.clone().unwrap_or_default(),
+            "schemes": http.schemes.clone().unwrap_or_default(),
+            "max_requests": http.max_requests,
+            "timeout_ms": http.timeout_ms,
+        })
+    });
+    let json = serde_json::json!({
+        "preopens": preopens,
+        "env": caps.env,
+        "stdio": stdio,
+        "network": caps.network.unwrap_or(false),
+        "http": http,
+    });
+    serde_json::to_string(&json.to_string()).map_err(|e| e.to_string())
+}
+
+/// See `assemblylift_core::runtime_environment::Interpreter`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Interpreter {
+    /// Name of the runtime environment
+    pub name: String,
+    pub version: String,
+    pub assets: String,
+    pub source_dir: String,
+}
+
 #[derive(Serialize, Deserialize, Clone)]
 pub struct Http {
     pub verb: String,
@@ -67,6 +67,12 @@ impl Manifest {
             size_mb: None,
             cpu_compat_mode: None,
             precompile: None,
+            architectures: None,
+            components: None,
+            tmp_quota_mb: None,
+            iomod_timeout_ms: None,
+            persistent_cache: None,
+            capabilities: None,
             environment: None,
         };
         functions.push(fun);
@@ -138,8 +144,40 @@ pub struct Function {
     pub size_mb: Option<u16>,
     pub cpu_compat_mode: Option<String>,
     pub precompile: Option<bool>,
+    pub architectures: Option<Vec<String>>,
+    pub components: Option<Vec<String>>,
+    pub tmp_quota_mb: Option<u32>,
+    pub iomod_timeout_ms: Option<u32>,
+    pub persistent_cache: Option<bool>,
     pub http: Option<HttpFunction>,
     pub environment: Option<StringMap<String>>,
+    pub capabilities: Option<Capabilities>,
+}
+
+/// WASI capabilities granted to the function; see `assemblylift_core::wasm::capabilities`
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Capabilities {
+    pub preopens: Option<Vec<Preopen>>,
+    pub env: Option<Vec<String>>,
+    pub stdio: Option<String>,
+    pub network: Option<bool>,
+    pub http: Option<HttpCapability>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct HttpCapability {
+    pub hosts: Option<Vec<String>>,
+    pub methods: Option<Vec<String>>,
+    pub schemes: Option<Vec<String>>,
+    pub max_requests: Option<u32>,
+    pub timeout_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Preopen {
+    pub host_path: String,
+    pub guest_path: String,
+    pub writable: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Clone)]
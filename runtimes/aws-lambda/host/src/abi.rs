@@ -1,4 +1,8 @@
-use assemblylift_core::wasm::StatusTx;
+use base64::{engine::general_purpose, Engine as _};
+use serde_json::json;
+use tracing::{error, warn};
+
+use assemblylift_core::wasm::{asml_rt, StatusTx};
 use assemblylift_core::{KeysAbi, RuntimeAbi, SecretsAbi};
 use assemblylift_wasi_secrets_in_memory::InMemorySecrets;
 
@@ -6,6 +10,8 @@ use assemblylift_wasi_secrets_in_memory::InMemorySecrets;
 pub enum Status {
     Success((Option<String>, serde_json::Value)),
     Failure((Option<String>, serde_json::Value)),
+    /// The guest's response isn't valid JSON; carries the parse error
+    InvalidResponse((Option<String>, String)),
 }
 
 pub struct Abi;
@@ -34,16 +40,73 @@ impl SecretsAbi for Abi {
 
 impl RuntimeAbi<Status> for Abi {
     fn success(status_tx: StatusTx<Status>, response: Vec<u8>, request_id: Option<String>) {
-        let response = serde_json::from_slice(response.as_slice()).unwrap();
-        status_tx
-            .send(Status::Success((request_id, response)))
-            .unwrap();
+        let status = match serde_json::from_slice(response.as_slice()) {
+            Ok(response) => Status::Success((request_id, response)),
+            Err(err) => invalid_response(request_id, err),
+        };
+        send(status_tx, status);
     }
 
     fn failure(status_tx: StatusTx<Status>, response: Vec<u8>, request_id: Option<String>) {
-        let response = serde_json::from_slice(response.as_slice()).unwrap();
-        status_tx
-            .send(Status::Failure((request_id, response)))
-            .unwrap();
+        let status = match serde_json::from_slice(response.as_slice()) {
+            Ok(response) => Status::Failure((request_id, response)),
+            Err(err) => invalid_response(request_id, err),
+        };
+        send(status_tx, status);
+    }
+
+    fn respond(
+        status_tx: StatusTx<Status>,
+        response: asml_rt::HttpResponse,
+        request_id: Option<String>,
+    ) {
+        // API Gateway takes one value per header in `headers`
+        let mut headers = serde_json::Map::new();
+        for (name, value) in response.headers {
+            let value = match headers.remove(&name) {
+                Some(serde_json::Value::String(prev)) => format!("{}, {}", prev, value),
+                _ => value,
+            };
+            headers.insert(name, serde_json::Value::String(value));
+        }
+        let response = json!({
+            "statusCode": response.status,
+            "headers": headers,
+            "isBase64Encoded": true,
+            "body": general_purpose::STANDARD.encode(response.body),
+        });
+        send(status_tx, Status::Success((request_id, response)));
+    }
+}
+
+fn invalid_response(request_id: Option<String>, err: serde_json::Error) -> Status {
+    Status::InvalidResponse((request_id, format!("function response is not valid JSON: {}", err)))
+}
+
+// An invocation has a single status, read once the guest returns. A guest which responds and then
+// fails (e.g. by exceeding a limit) sends a second status, which is dropped rather than blocking the guest.
+fn send(status_tx: StatusTx<Status>, status: Status) {
+    match status_tx.try_send(status) {
+        Ok(()) => {}
+        Err(err) if err.is_full() => warn!("dropping status; the invocation already has one"),
+        Err(_) => error!("could not send status: the invocation has ended"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use assemblylift_core::wasm::status_channel;
+    use assemblylift_core::RuntimeAbi;
+
+    use super::{Abi, Status};
+
+    #[test]
+    fn test_second_status_is_dropped() {
+        let (status_tx, status_rx) = status_channel::<Status>(1);
+        Abi::success(status_tx.clone(), b"{}".to_vec(), None);
+        // Returns, rather than waiting for room in the channel
+        Abi::failure(status_tx.clone(), b"{\"error\":\"memory limit exceeded\"}".to_vec(), None);
+        assert!(matches!(status_rx.try_recv(), Ok(Status::Success(_))));
+        assert!(status_rx.try_recv().is_err());
     }
 }
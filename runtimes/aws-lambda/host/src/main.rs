@@ -6,14 +6,23 @@ use std::io::{BufReader, Read};
 use std::os::unix::fs::PermissionsExt;
 use std::path::{Path, PathBuf};
 use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use clap::crate_version;
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
-use tracing::{error, info, Level};
+use tracing::{error, info, info_span, warn, Instrument, Level};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::FmtSubscriber;
 use zip;
 
-use assemblylift_core::wasm::{status_channel, Wasmtime};
+use assemblylift_core::threader::{iomod_versions_from_json, IomodVersions};
+use assemblylift_core::{runtime_environment, telemetry};
+use assemblylift_core::wasm::capabilities::{Capabilities, Preopen};
+use assemblylift_core::wasm::{
+    is_limit_exceeded, is_timeout, scratch, status_channel, Entrypoint, FunctionLimits,
+    FunctionStore, HandlerFunc, InvocationError, Wasmtime,
+};
 use assemblylift_core_iomod::registry::registry_channel;
 use assemblylift_core_iomod::{package::IomodManifest, registry};
 
@@ -21,6 +30,9 @@ use crate::abi::{Abi, Status};
 
 mod abi;
 
+// Time reserved at the end of an invocation to report a timeout before Lambda itself kills the function
+const DEADLINE_MARGIN: Duration = Duration::from_millis(250);
+
 #[tokio::main]
 async fn main() -> Result<(), Error> {
     let subscriber = FmtSubscriber::builder()
@@ -28,7 +40,8 @@ async fn main() -> Result<(), Error> {
         .with_target(false)
         .with_ansi(false)
         .without_time()
-        .finish();
+        .finish()
+        .with(telemetry::otlp_layer("assemblylift-aws-lambda-runtime")?);
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
@@ -42,8 +55,8 @@ async fn main() -> Result<(), Error> {
     let (registry_tx, registry_rx) = registry_channel(32);
     registry::spawn_registry(registry_rx).unwrap();
 
-    // Mapped to /tmp inside the WASM module
-    fs::create_dir_all("/tmp/asmltmp").expect("could not create /tmp/asmltmp");
+    // Holds the per-invocation directories mapped to /tmp inside the WASM module
+    scratch::init_root().expect("could not create scratch directory root");
 
     // Load IOmod packages from /opt, which should contain merged contents of Lambda layers
     if let Ok(rd) = fs::read_dir("/opt") {
@@ -101,7 +114,10 @@ async fn main() -> Result<(), Error> {
                                         entrypoint_file.set_permissions(perms)
                                             .expect("could not set IOmod binary executable (octal 755) permissions");
                                     }
-                                    process::Command::new(path).spawn().unwrap();
+                                    process::Command::new(path)
+                                        .env("ASML_IOMOD_VERSION", &iomod_manifest.iomod.version)
+                                        .spawn()
+                                        .unwrap();
                                 }
                             }
                         }
@@ -111,58 +127,64 @@ async fn main() -> Result<(), Error> {
         }
     }
 
-    let runtime_environment = std::env::var("ASML_FUNCTION_ENV");
+    let environment = std::env::var("ASML_FUNCTION_ENV")
+        .ok()
+        .and_then(|name| runtime_environment::by_name(&name));
 
-    // Copy Ruby env to /tmp
-    if let Ok("ruby") = runtime_environment.as_deref() {
-        let rubysrc_path = "/tmp/rubysrc";
-        if !Path::new(&rubysrc_path).exists() {
-            fs::create_dir_all(rubysrc_path)
-                .expect(&*format!("unable to create directory {:?}", rubysrc_path));
-        }
-        let rubyusr_path = "/tmp/rubyusr";
-        if !Path::new(&rubyusr_path).exists() {
-            fs::create_dir_all(rubyusr_path)
-                .expect(&*format!("unable to create directory {:?}", rubyusr_path));
-        }
+    // Copy the interpreter's bind paths to /tmp
+    let mut bind_paths: Vec<(String, String)> = Vec::new();
+    if let Some(environment) = environment {
+        if let Some(interpreter) = &environment.interpreter {
+            fn copy_entries(dir: &PathBuf, to: &PathBuf) {
+                for entry in fs::read_dir(dir).unwrap() {
+                    let entry = entry.unwrap();
+                    if entry.file_type().unwrap().is_file() {
+                        let copy_to = format!(
+                            "{}/{}",
+                            to.to_str().unwrap(),
+                            entry.file_name().to_str().unwrap()
+                        );
+                        fs::copy(entry.path(), copy_to).unwrap();
+                    } else if entry.file_type().unwrap().is_dir() {
+                        let mut copy_to = PathBuf::from(to);
+                        copy_to.push(entry.path().iter().last().unwrap());
+                        fs::create_dir_all(&copy_to).unwrap();
+                        copy_entries(&entry.path(), &copy_to);
+                    }
+                }
+            }
 
-        fn copy_entries(dir: &PathBuf, to: &PathBuf) {
-            for entry in fs::read_dir(dir).unwrap() {
-                let entry = entry.unwrap();
-                if entry.file_type().unwrap().is_file() {
-                    let copy_to = format!(
-                        "{}/{}",
-                        to.to_str().unwrap(),
-                        entry.file_name().to_str().unwrap()
-                    );
-                    fs::copy(entry.path(), copy_to).unwrap();
-                } else if entry.file_type().unwrap().is_dir() {
-                    let mut copy_to = PathBuf::from(to);
-                    copy_to.push(entry.path().iter().last().unwrap());
-                    fs::create_dir_all(&copy_to).unwrap();
-                    copy_entries(&entry.path(), &copy_to);
+            let source_dir = PathBuf::from(format!("{}/{}", &module_path, interpreter.source_dir));
+            // Interpreter assets are provided by a layer, which is merged into /opt
+            let assets_dir = PathBuf::from(format!("/opt/{}", interpreter.assets));
+            for (host_path, guest_path) in environment.resolve_bind_paths(&source_dir, &assets_dir) {
+                let copy_path = format!("/tmp/{}{}", environment.name, guest_path);
+                if !Path::new(&copy_path).exists() {
+                    fs::create_dir_all(&copy_path)
+                        .expect(&*format!("unable to create directory {:?}", copy_path));
                 }
+                copy_entries(&PathBuf::from(host_path), &PathBuf::from(&copy_path));
+                bind_paths.push((copy_path, guest_path));
             }
         }
-        copy_entries(
-            &PathBuf::from(format!("{}/rubysrc", &module_path)),
-            &PathBuf::from(rubysrc_path),
-        );
-        copy_entries(
-            &PathBuf::from("/opt/ruby-wasm32-wasi/usr"),
-            &PathBuf::from(rubyusr_path),
-        );
     }
 
     let mut full_path = PathBuf::from(&module_path);
     full_path.push(&handler_name);
-    let wasmtime = RefCell::new(
-        Wasmtime::<Abi, Status>::new_from_path(Path::new(full_path.as_path()))
-            .expect("could not create wasm runtime from module path"),
-    );
+    // Without a function to run, every invocation fails with the same error
+    let wasmtime = Wasmtime::<Abi, Status>::new_from_path(Path::new(full_path.as_path())).map(RefCell::new);
+    if let Err(err) = &wasmtime {
+        error!("could not load function {}: {}", full_path.display(), err.to_string());
+    }
+
+    // Lambda runs one invocation at a time, so a reactor function needs at most one warm instance
+    let warm_reactor: RefCell<Option<(HandlerFunc, FunctionStore<Abi, Status>)>> = RefCell::new(None);
 
     let wasmtime_ref = &wasmtime;
+    let warm_reactor_ref = &warm_reactor;
     let registry_tx_ref = &registry_tx;
+    let handler_name_ref = &handler_name;
+    let bind_paths_ref = &bind_paths;
     run(service_fn(
         move |event: LambdaEvent<serde_json::Value>| async move {
             // Environment vars prefixed with __ASML_ are defined in the function definition;
@@ -177,49 +199,167 @@ async fn main() -> Result<(), Error> {
                     .map(|e| (e.0.replace("__ASML_", ""), e.1))
                     .into_iter(),
             );
-            let runtime_environment = std::env::var("ASML_FUNCTION_ENV");
-            let bind_paths: Vec<(String, String)> = match runtime_environment.as_deref().ok() {
-                Some("ruby") => vec![("/tmp/rubysrc".into(), "/src".into()), ("/tmp/rubyusr".into(), "/usr".into())],
-                Some(_) | None => Vec::new(),
+            let memory_size_mb = std::env::var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE")
+                .ok()
+                .and_then(|size| size.parse::<u32>().ok());
+            let tmp_quota_mb = std::env::var("ASML_FUNCTION_TMP_QUOTA_MB")
+                .ok()
+                .and_then(|size| size.parse::<u32>().ok())
+                .or(Some(scratch::DEFAULT_TMP_QUOTA_MB));
+            let iomod_timeout = std::env::var("ASML_FUNCTION_IOMOD_TIMEOUT_MS")
+                .ok()
+                .and_then(|ms| ms.parse::<u64>().ok())
+                .map(Duration::from_millis);
+            let iomod_versions = match std::env::var("ASML_FUNCTION_IOMODS") {
+                Ok(iomods) => iomod_versions_from_json(&iomods)
+                    .map_err(|e| bad_config("ASML_FUNCTION_IOMODS", e))?,
+                Err(_) => IomodVersions::default(),
+            };
+            let mut capabilities = match std::env::var("ASML_FUNCTION_CAPABILITIES") {
+                Ok(caps) => Capabilities::from_json(&caps)
+                    .map_err(|e| bad_config("ASML_FUNCTION_CAPABILITIES", e))?,
+                Err(_) => Capabilities::default(),
             };
+            if let Ok("true") = std::env::var("ASML_FUNCTION_PERSISTENT_CACHE").as_deref() {
+                // Only survives for the lifetime of the execution environment
+                let cache_dir = scratch::function_cache_dir(handler_name_ref).map_err(|e| {
+                    let message = format!("could not create cache directory: {}", e);
+                    invocation_error(&InvocationError::Unavailable(message))
+                })?;
+                capabilities.preopens.push(Preopen {
+                    host_path: cache_dir.to_string_lossy().to_string(),
+                    guest_path: "/cache".into(),
+                    writable: true,
+                });
+            }
             let (status_tx, status_rx) = status_channel::<Status>(1);
             let request_id = &event.context.request_id;
-            
-            let (command, mut store) = wasmtime_ref
-                .borrow_mut()
-                .link_wasi_component(
-                    registry_tx_ref.clone(),
+            let timeout = Duration::from_millis(event.context.deadline)
+                .checked_sub(SystemTime::now().duration_since(UNIX_EPOCH).unwrap())
+                .map(|remaining| remaining.saturating_sub(DEADLINE_MARGIN));
+            let input = event.payload.to_string().into_bytes();
+
+            // API Gateway events carry the caller's headers, which may continue its trace
+            let span = info_span!("invocation", function = %handler_name_ref, request_id = %request_id);
+            let traceparent = event
+                .payload
+                .get("headers")
+                .and_then(|headers| headers.get("traceparent"))
+                .and_then(|traceparent| traceparent.as_str());
+            span.set_parent(telemetry::context_from(traceparent));
+
+            let wasmtime_ref = match wasmtime_ref {
+                Ok(wasmtime) => wasmtime,
+                Err(err) => return Err(invocation_error(err)),
+            };
+
+            // A warm instance whose /tmp can't be emptied is dropped, and a fresh one linked instead
+            let warm = warm_reactor_ref.borrow_mut().take().and_then(|(handler, mut store)| {
+                match store.data_mut().begin_invocation(
                     status_tx.clone(),
-                    environment_vars,
-                    runtime_environment.clone().unwrap_or("default".to_string()),
-                    bind_paths,
                     Some(String::from(request_id)),
-                    &event.payload.to_string().as_bytes(),
-                )
-                .await
-                .expect("could not link wasm module");
-
-            return match wasmtime_ref
-                .borrow_mut()
-                .run_component(
-                    command,
-                    &mut store,
-                )
-                .await
-            {
+                    input.clone(),
+                    timeout,
+                ) {
+                    Ok(()) => Some((handler, store)),
+                    Err(err) => {
+                        warn!("could not reset warm instance: {}", err.to_string());
+                        None
+                    }
+                }
+            });
+            let (entrypoint, mut store) = match warm {
+                Some((handler, store)) => (Entrypoint::Reactor(handler), store),
+                None => match wasmtime_ref
+                    .borrow()
+                    .link_wasi_component(
+                        registry_tx_ref.clone(),
+                        status_tx.clone(),
+                        environment_vars,
+                        environment.map(|e| e.name).unwrap_or("default").to_string(),
+                        bind_paths_ref.clone(),
+                        &capabilities,
+                        FunctionLimits {
+                            timeout,
+                            memory_size_mb,
+                            tmp_quota_mb,
+                            iomod_timeout,
+                        },
+                        iomod_versions,
+                        Some(String::from(request_id)),
+                        &input,
+                    )
+                    .instrument(info_span!(parent: &span, "link"))
+                    .await
+                {
+                    Ok(linked) => linked,
+                    Err(err) => {
+                        error!("event id {}: {}", &request_id, err.to_string());
+                        drop(span);
+                        flush_spans().await;
+                        return Err(invocation_error(&err));
+                    }
+                },
+            };
+
+            let (result, reactor) = match entrypoint {
+                Entrypoint::Command(command) => (
+                    wasmtime_ref
+                        .borrow()
+                        .run_component(command, &mut store)
+                        .instrument(info_span!(parent: &span, "run"))
+                        .await,
+                    None,
+                ),
+                Entrypoint::Reactor(handler) => (
+                    wasmtime_ref
+                        .borrow()
+                        .call_handler(&handler, &mut store)
+                        .instrument(info_span!(parent: &span, "run"))
+                        .await,
+                    Some(handler),
+                ),
+            };
+            // The execution environment may be frozen as soon as we respond
+            drop(span);
+            flush_spans().await;
+
+            return match result {
                 Ok(_) => {
                     info!("handler for event {} returned OK", request_id);
+                    // Keep a reactor's instance for the next invocation; it is dropped after an error
+                    if let Some(handler) = reactor {
+                        *warm_reactor_ref.borrow_mut() = Some((handler, store));
+                    }
                     match status_rx.recv() {
                         Ok(status) => match status {
                             Status::Success(s) => Ok(s.1),
                             Status::Failure(s) => Err(Error::from(s.1.to_string())),
+                            Status::InvalidResponse(s) => {
+                                error!("event id {}: {}", &request_id, &s.1);
+                                Err(Error::from(format!("InvalidResponse: {}", s.1)))
+                            }
                         },
                         Err(err) => Err(Error::from(err)),
                     }
                 }
-                Err(err) => {
+                Err(err) if is_timeout(&err) => {
+                    error!("event id {}: function timed out", &request_id);
+                    Err(Error::from("function timed out"))
+                }
+                Err(err) if is_limit_exceeded(&err) => {
                     error!("event id {}: {}", &request_id, err.to_string());
-                    Err(Error::from(err))
+                    match status_rx.recv() {
+                        Ok(Status::Failure(s)) => Err(Error::from(s.1.to_string())),
+                        _ => Err(Error::from(err)),
+                    }
+                }
+                Err(err) => {
+                    let coordinates = std::env::var("ASML_FUNCTION_COORDINATES")
+                        .or(std::env::var("AWS_LAMBDA_FUNCTION_NAME"))
+                        .ok();
+                    let body = store.data().crash_body(&err, coordinates);
+                    Err(Error::from(String::from_utf8_lossy(&body).to_string()))
                 }
             };
         },
@@ -227,3 +367,28 @@ async fn main() -> Result<(), Error> {
     .await?;
     Ok(())
 }
+
+async fn flush_spans() {
+    if let Err(err) = tokio::task::spawn_blocking(telemetry::flush).await {
+        error!("could not flush spans: {}", err.to_string());
+    }
+}
+
+/// The function's configuration variable `name` has an invalid value
+fn bad_config(name: &str, err: anyhow::Error) -> Error {
+    let err = InvocationError::BadConfig(format!("{}: {:#}", name, err));
+    error!("{}", err.to_string());
+    invocation_error(&err)
+}
+
+/// Lambda reports a handler error by its type & message; the type carries the equivalent HTTP status
+fn invocation_error(err: &InvocationError) -> Error {
+    let error_type = match err {
+        InvocationError::BadRequest(_) => "BadRequest",
+        InvocationError::NotFound(_) => "NotFound",
+        InvocationError::Unavailable(_) => "Unavailable",
+        InvocationError::BadConfig(_) => "BadConfig",
+        InvocationError::Failed(_) => "InvocationFailed",
+    };
+    Error::from(format!("{}: {}", error_type, err.to_string()))
+}
@@ -1,6 +1,7 @@
+use crossbeam_channel::TrySendError;
 use tracing::error;
 
-use assemblylift_core::wasm::StatusTx;
+use assemblylift_core::wasm::{asml_rt, StatusTx};
 use assemblylift_core::{KeysAbi, RuntimeAbi, SecretsAbi};
 use assemblylift_wasi_secrets_in_memory::InMemorySecrets;
 
@@ -30,6 +31,7 @@ impl SecretsAbi for Abi {
     }
 }
 
+#[async_trait::async_trait]
 impl RuntimeAbi<Status> for Abi {
     fn success(status_tx: StatusTx<Status>, response: Vec<u8>, _request_id: Option<String>) {
         std::thread::spawn(move || {
@@ -46,4 +48,55 @@ impl RuntimeAbi<Status> for Abi {
             }
         });
     }
+
+    fn respond(
+        status_tx: StatusTx<Status>,
+        response: asml_rt::HttpResponse,
+        _request_id: Option<String>,
+    ) {
+        std::thread::spawn(move || {
+            if let Err(e) = status_tx.send(Status::Respond(response)) {
+                error!("could not send status: {:?}", e.to_string())
+            }
+        });
+    }
+
+    // Each part is sent before the guest continues, so that chunks arrive in order. A client which
+    // reads slower than the guest writes fills the status channel, and then holds the guest back.
+    const STREAMING: bool = true;
+
+    async fn begin_response(
+        status_tx: StatusTx<Status>,
+        status: u16,
+        headers: Vec<(String, String)>,
+        _request_id: Option<String>,
+    ) {
+        send_stream_status(status_tx, Status::BeginResponse(status, headers)).await
+    }
+
+    async fn write_chunk(status_tx: StatusTx<Status>, chunk: Vec<u8>, _request_id: Option<String>) {
+        send_stream_status(status_tx, Status::Chunk(chunk)).await
+    }
+
+    async fn finish_response(status_tx: StatusTx<Status>, _request_id: Option<String>) {
+        send_stream_status(status_tx, Status::FinishResponse).await
+    }
+}
+
+/// Send part of a streamed response. While the status channel is full, the guest waits off the runner's
+/// thread, which carries on running other functions.
+async fn send_stream_status(status_tx: StatusTx<Status>, status: Status) {
+    let result = match status_tx.try_send(status) {
+        Ok(()) => Ok(()),
+        Err(TrySendError::Full(status)) => {
+            match tokio::task::spawn_blocking(move || status_tx.send(status)).await {
+                Ok(result) => result.map_err(|e| e.to_string()),
+                Err(e) => Err(e.to_string()),
+            }
+        }
+        Err(e) => Err(e.to_string()),
+    };
+    if let Err(e) = result {
+        error!("could not send status: {:?}", e)
+    }
 }
@@ -1,8 +1,9 @@
 use std::collections::BTreeMap;
 use std::net::SocketAddr;
 use std::ops::Deref;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
 use anyhow::anyhow;
 use hyper::body::HttpBody;
@@ -12,13 +13,20 @@ use hyper::{Body, Request, Response, Server};
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, error, info, warn};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 use url::Url;
 
-use assemblylift_core::wasm::{status_channel, StatusRx, StatusTx};
+use assemblylift_core::telemetry;
+use assemblylift_core::threader::iomod_versions_from_json;
+use assemblylift_core::wasm::capabilities::{Capabilities, Preopen};
+use assemblylift_core::wasm::{asml_rt, scratch, status_channel, InvocationError, StatusRx};
 
+use crate::metrics::{function_label, INVOCATIONS};
 use crate::runner::{RunnerMessage, RunnerTx};
 use crate::Status;
-use crate::Status::{Exited, Failure, Success};
+use crate::Status::{
+    BeginResponse, Chunk, Error, Exited, Failure, FinishResponse, Respond, Success, Timeout,
+};
 
 pub const INSTALL_DIR: Lazy<String> =
     Lazy::new(|| std::env::var("ASML_INSTALL_DIR").unwrap_or("/opt/assemblylift".to_string()));
@@ -28,8 +36,34 @@ pub const FUNCTION_COORDINATES: Lazy<Option<String>> =
     Lazy::new(|| std::env::var("ASML_FUNCTION_COORDINATES").ok());
 pub const FUNCTION_PRECOMPILED: Lazy<Option<String>> = 
     Lazy::new(|| std::env::var("ASML_FUNCTION_PRECOMPILED").ok());
+pub const FUNCTION_TIMEOUT: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_TIMEOUT").ok());
+pub const FUNCTION_SIZE_MB: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_SIZE_MB").ok());
+pub const FUNCTION_TMP_QUOTA_MB: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_TMP_QUOTA_MB").ok());
+pub const FUNCTION_IOMOD_TIMEOUT_MS: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_IOMOD_TIMEOUT_MS").ok());
+pub const FUNCTION_IOMODS: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_IOMODS").ok());
+pub const FUNCTION_PERSISTENT_CACHE: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_PERSISTENT_CACHE").ok());
+pub const FUNCTION_CAPABILITIES: Lazy<Option<String>> =
+    Lazy::new(|| std::env::var("ASML_FUNCTION_CAPABILITIES").ok());
 pub const MAX_ALLOWED_REQUEST_SIZE: u64 = 10_485_760;
 
+// Limits applied when the deployment doesn't set them; these match the generator's defaults
+pub const DEFAULT_FUNCTION_TIMEOUT_SECS: u64 = 5;
+pub const DEFAULT_FUNCTION_SIZE_MB: u32 = 1024;
+
+/// Headers which once configured the guest from the request. A caller could use them to run any
+/// component, or to mount any host directory, so requests which set them are refused.
+const REFUSED_HEADERS: &[&str] = &[
+    "x-assemblylift-wasm-uri",
+    "x-assemblylift-function-env-vars",
+    "x-assemblylift-function-bind-paths",
+];
+
 pub struct Launcher {
     runtime: tokio::runtime::Runtime,
 }
@@ -47,16 +81,12 @@ impl Launcher {
     pub fn spawn(&mut self, runner_tx: RunnerTx<Status>) {
         info!("Spawning launcher");
         tokio::task::LocalSet::new().block_on(&self.runtime, async {
-            let channel = status_channel(32);
-
             let make_svc = make_service_fn(|_| {
                 debug!("called make_service_fn");
                 let runner_tx = runner_tx.clone();
-                let tx = channel.0.clone();
-                let rx = channel.1.clone();
                 async {
                     Ok::<_, anyhow::Error>(service_fn(move |req| {
-                        launch(req, runner_tx.clone(), tx.clone(), rx.clone())
+                        launch(req, runner_tx.clone())
                     }))
                 }
             });
@@ -70,25 +100,40 @@ impl Launcher {
     }
 }
 
-async fn launch(
+async fn launch(req: Request<Body>, runner_tx: RunnerTx<Status>) -> anyhow::Result<Response<Body>> {
+    match try_launch(req, runner_tx).await {
+        Ok(response) => Ok(response),
+        Err(err) => {
+            warn!("{}", err.to_string());
+            Ok(error_response(err.status_code(), err.to_string()))
+        }
+    }
+}
+
+async fn try_launch(
     req: Request<Body>,
     runner_tx: RunnerTx<Status>,
-    status_tx: StatusTx<Status>,
-    status_rx: StatusRx<Status>,
-) -> anyhow::Result<Response<Body>> {
+) -> Result<Response<Body>, InvocationError> {
     debug!("launching function...");
+    // Each request has its own channel, so that a streamed response only receives its own chunks
+    let (status_tx, status_rx) = status_channel::<Status>(32);
     let method = req.method().to_string();
     let path = req.uri().path().to_string();
     let mut headers = BTreeMap::new();
     for h in req.headers().iter() {
-        headers.insert(h.0.as_str().to_string(), h.1.to_str().unwrap().to_string());
+        let value = h.1.to_str().map_err(|_| {
+            InvocationError::BadRequest(format!("header {} is not valid text", h.0.as_str()))
+        })?;
+        headers.insert(h.0.as_str().to_string(), value.to_string());
     }
     let request_content_length = match req.body().size_hint().upper() {
         Some(v) => v,
         None => MAX_ALLOWED_REQUEST_SIZE + 1,
     };
     let input_bytes = match request_content_length < MAX_ALLOWED_REQUEST_SIZE {
-        true => hyper::body::to_bytes(req.into_body()).await.unwrap(),
+        true => hyper::body::to_bytes(req.into_body())
+            .await
+            .map_err(|e| InvocationError::BadRequest(format!("could not read request body: {}", e)))?,
         false => {
             warn!(
                 "function request payload exceeds limit of {} bytes",
@@ -115,11 +160,11 @@ async fn launch(
         None => "wasm.bin",
     };
 
-    fn uri_from_coords(coords: &String, ext: &str) -> anyhow::Result<Url> {
+    fn uri_from_coords(coords: &String, ext: &str) -> Result<Url, InvocationError> {
         // coordinate is the triple project.service.function
         let coordinates = coords.split('.').collect::<Vec<&str>>();
-        if coordinates.len() != 3 {
-            return Err(anyhow!("malformed coordinates in header"));
+        if coordinates.len() != 3 || !coordinates.iter().all(|part| is_coordinate(part)) {
+            return Err(InvocationError::BadRequest(format!("malformed coordinates {}", coords)));
         }
         let project_dir = PathBuf::from(format!(
             "{}/projects/{}",
@@ -137,7 +182,7 @@ async fn launch(
                 )
                 .as_str(),
             )
-            .map_err(|e| anyhow!(e)),
+            .map_err(|e| InvocationError::BadRequest(e.to_string())),
 
             false => match PathBuf::from("./assemblylift.toml").exists() {
                 true => Url::from_str(
@@ -151,57 +196,156 @@ async fn launch(
                     )
                     .as_str(),
                 )
-                .map_err(|e| anyhow!(e)),
-                false => return Err(anyhow!("cannot find function to run; assemblylift is not installed or we are not in a project directory")),
+                .map_err(|e| InvocationError::BadRequest(e.to_string())),
+                false => return Err(InvocationError::NotFound(
+                    "cannot find function to run; assemblylift is not installed or we are not in a project directory".into(),
+                )),
             },
         }
     }
 
-    let wasm_uri: Url = match FUNCTION_COORDINATES.deref() {
-        Some(coords) => uri_from_coords(coords, wasm_ext)?,
-        None => match headers.get("x-assemblylift-function-coordinates") {
-            Some(coords) => uri_from_coords(coords, wasm_ext)?,
-            None => Url::from_str(&**headers.get("x-assemblylift-wasm-uri").unwrap())
-                .map_err(|e| anyhow!(e))?,
-        }
+    if let Some(header) = REFUSED_HEADERS.iter().find(|h| headers.contains_key(**h)) {
+        return Err(InvocationError::BadRequest(format!(
+            "{} is not accepted; functions are configured by their deployment",
+            header
+        )));
+    }
+
+    // Only functions installed on this host can be invoked, by their coordinates
+    let coordinates = match FUNCTION_COORDINATES.deref() {
+        Some(coords) => coords.clone(),
+        None => headers
+            .get("x-assemblylift-function-coordinates")
+            .cloned()
+            .ok_or_else(|| InvocationError::BadRequest("no function coordinates given".into()))?,
     };
+    let wasm_uri: Url = uri_from_coords(&coordinates, wasm_ext)?;
+    let coordinates = Some(coordinates);
 
     if !wasm_uri.scheme().eq_ignore_ascii_case("file") {
-        unimplemented!("{} scheme not yet supported", wasm_uri.scheme());
+        return Err(InvocationError::BadRequest(format!(
+            "{} scheme not yet supported",
+            wasm_uri.scheme()
+        )));
     }
 
-    let env_vars: BTreeMap<String, String> = match headers.get("x-assemblylift-function-env-vars") {
-        Some(vars) => parse_map(vars),
-        None => Default::default(),
-    };
+    // The guest's environment comes from the deployment (`__ASML_` variables, read by the runner)
+    let env_vars: BTreeMap<String, String> = Default::default();
 
-    let mut bind_paths: BTreeMap<String, String> = match headers.get("x-assemblylift-function-bind-paths") {
-        Some(paths) => parse_map(paths),
+    let bind_paths: BTreeMap<String, String> = match FUNCTION_BIND_PATHS.deref() {
+        // Set by the host rather than the request, so a malformed value is our own fault
+        Some(paths) => parse_map(paths).map_err(|err| {
+            InvocationError::BadConfig(format!("ASML_FUNCTION_BIND_PATHS: {}", err))
+        })?,
         None => Default::default(),
     };
-    if let Some(paths) =  FUNCTION_BIND_PATHS.deref() {
-        bind_paths.append(&mut parse_map(paths));
-    }
 
     let runtime_environment = headers.get("x-assemblylift-function-runtime-env").cloned();
+
+    // Tags the guest's log output; use the caller's request id if it gave one
+    let request_id = headers
+        .get("x-request-id")
+        .cloned()
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    // Policy & limits are set by the deployment, never by the request; when unset, the defaults are restrictive
+    let timeout = Some(Duration::from_secs(
+        FUNCTION_TIMEOUT
+            .deref()
+            .as_ref()
+            .and_then(|secs| secs.parse::<u64>().ok())
+            .unwrap_or(DEFAULT_FUNCTION_TIMEOUT_SECS),
+    ));
+
+    let memory_size_mb = Some(
+        FUNCTION_SIZE_MB
+            .deref()
+            .as_ref()
+            .and_then(|size| size.parse::<u32>().ok())
+            .unwrap_or(DEFAULT_FUNCTION_SIZE_MB),
+    );
+
+    let tmp_quota_mb = Some(
+        FUNCTION_TMP_QUOTA_MB
+            .deref()
+            .as_ref()
+            .and_then(|size| size.parse::<u32>().ok())
+            .unwrap_or(scratch::DEFAULT_TMP_QUOTA_MB),
+    );
+
+    let iomod_timeout = FUNCTION_IOMOD_TIMEOUT_MS
+        .deref()
+        .as_ref()
+        .and_then(|ms| ms.parse::<u64>().ok())
+        .map(Duration::from_millis);
+
+    // Set by the host rather than the request, so a malformed value is our own fault
+    let iomod_versions = FUNCTION_IOMODS
+        .deref()
+        .as_ref()
+        .map(|iomods| iomod_versions_from_json(iomods))
+        .transpose()
+        .map_err(|e| InvocationError::BadConfig(format!("ASML_FUNCTION_IOMODS: {:#}", e)))?
+        .unwrap_or_default();
+
+    let mut capabilities = FUNCTION_CAPABILITIES
+        .deref()
+        .as_ref()
+        .map(|caps| Capabilities::from_json(caps))
+        .transpose()
+        .map_err(|e| InvocationError::BadConfig(format!("ASML_FUNCTION_CAPABILITIES: {:#}", e)))?
+        .unwrap_or_default();
+
+    let persistent_cache = FUNCTION_PERSISTENT_CACHE
+        .deref()
+        .as_ref()
+        .map_or(false, |enabled| enabled.eq_ignore_ascii_case("true"));
+    if persistent_cache {
+        // The cache is keyed on the module path, which is unique per function
+        let cache_dir = scratch::function_cache_dir(wasm_uri.path()).map_err(|e| {
+            InvocationError::Unavailable(format!("could not create cache directory: {}", e))
+        })?;
+        capabilities.preopens.push(Preopen {
+            host_path: cache_dir.to_string_lossy().to_string(),
+            guest_path: "/cache".into(),
+            writable: true,
+        });
+    }
+
+    let function = function_label(&coordinates, Path::new(wasm_uri.path()));
+    // Continues the caller's trace, if it sent a `traceparent`
+    let span = tracing::info_span!("invocation", function = %function, request_id = %request_id);
+    span.set_parent(telemetry::context_from(headers.get("traceparent").map(String::as_str)));
     let msg = RunnerMessage {
-        input: serde_json::to_vec(&launcher_req).unwrap(),
+        input: serde_json::to_vec(&launcher_req).map_err(|e| InvocationError::Failed(anyhow!(e)))?,
         status_sender: status_tx.clone(),
         wasm_path: PathBuf::from(wasm_uri.path()),
         env_vars,
         bind_paths,
         runtime_environment,
+        request_id,
+        coordinates,
+        traceparent: telemetry::traceparent(&span),
+        capabilities,
+        timeout,
+        memory_size_mb,
+        tmp_quota_mb,
+        iomod_timeout,
+        iomod_versions,
     };
 
     debug!("sending runner request...");
     runner_tx
         .send(msg)
         .await
-        .map_err(|e| anyhow!("could not send to runner: {}", e.to_string()))?;
+        .map_err(|e| InvocationError::Unavailable(format!("could not send to runner: {}", e)))?;
 
     debug!("waiting for runner response...");
     while let Ok(result) = status_rx.recv() {
         debug!("launcher received response from runner");
+        if let Some(outcome) = invocation_outcome(&result) {
+            INVOCATIONS.with_label_values(&[&function, outcome]).inc();
+        }
         return Ok(match result {
             Exited(status) => {
                 debug!("exit code {}", status);
@@ -214,50 +358,22 @@ async fn launch(
                 });
                 continue;
             }
-            Success(response) => match serde_json::from_slice::<serde_json::Value>(&response) {
-                Ok(json) => match json.get("isBase64Encoded").is_some() {
-                    true => {
-                        debug!("function response detected as Lambda-APIGW format");
-                        let b64 = json.get("isBase64Encoded").unwrap().as_bool().unwrap();
-                        let body = json
-                            .get("body")
-                            .unwrap()
-                            .as_str()
-                            .unwrap()
-                            .as_bytes()
-                            .to_vec();
-                        let mut response = Response::builder()
-                            .status(json.get("statusCode").unwrap().as_i64().unwrap() as u16)
-                            .body(Body::from(match b64 {
-                                true => base64::decode(body).unwrap(),
-                                false => body,
-                            }))
-                            .unwrap();
-                        let headers = json.get("headers").unwrap().as_object().unwrap().clone();
-                        for header in headers.into_iter() {
-                            let key = header.0.clone();
-                            response.headers_mut().insert(
-                                HeaderName::from_str(key.as_str()).unwrap(),
-                                HeaderValue::from_str(header.1.as_str().unwrap()).unwrap(),
-                            );
-                        }
-                        response
-                    }
-                    false => Response::builder()
-                        .status(200)
-                        .header("content-type", "application/json")
-                        .body(Body::from(response))
-                        .unwrap(),
-                },
-                Err(_) => Response::builder()
-                    .status(200)
-                    .body(Body::from(response))
-                    .unwrap(),
-            },
+            Success(response) => legacy_response(response),
+            Respond(response) => http_response(response),
+            BeginResponse(status, headers) => {
+                stream_response(status, headers, status_rx.clone(), function)
+            }
+            // Only sent after `BeginResponse`
+            Chunk(_) | FinishResponse => continue,
             Failure(response) => Response::builder()
                 .status(500)
                 .body(Body::from(response))
                 .unwrap(),
+            Timeout => Response::builder()
+                .status(504)
+                .body(Body::from("Function timed out"))
+                .unwrap(),
+            Error(status, message) => error_response(status, message),
         });
     }
 
@@ -267,14 +383,161 @@ async fn launch(
         .unwrap())
 }
 
-fn parse_map(vars: &String) -> BTreeMap<String, String> {
+/// The outcome recorded for an invocation which sent `status`, or `None` if it hasn't finished yet.
+/// A streamed response is recorded by `stream_response` once the guest finishes it.
+fn invocation_outcome(status: &Status) -> Option<&'static str> {
+    match status {
+        Success(_) | Respond(_) => Some("success"),
+        Failure(_) => Some("failure"),
+        Timeout => Some("timeout"),
+        Error(_, _) => Some("error"),
+        Exited(_) | BeginResponse(_, _) | Chunk(_) | FinishResponse => None,
+    }
+}
+
+fn error_response(status: u16, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(serde_json::json!({ "error": message }).to_string()))
+        .unwrap()
+}
+
+fn http_response(response: asml_rt::HttpResponse) -> Response<Body> {
+    build_response(response.status, response.headers, Body::from(response.body))
+}
+
+/// Respond with a body which is sent as the guest writes it, until it finishes the response or exits
+fn stream_response(
+    status: u16,
+    headers: Vec<(String, String)>,
+    status_rx: StatusRx<Status>,
+    function: String,
+) -> Response<Body> {
+    let (mut sender, body) = Body::channel();
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        let record = |outcome: &str| INVOCATIONS.with_label_values(&[&function, outcome]).inc();
+        while let Ok(status) = status_rx.recv() {
+            match status {
+                Chunk(chunk) => {
+                    if handle.block_on(sender.send_data(chunk.into())).is_err() {
+                        debug!("client went away during streamed response");
+                        record("cancelled");
+                        return;
+                    }
+                }
+                FinishResponse | Exited(_) => {
+                    record("success");
+                    return;
+                }
+                Failure(_) | Timeout => {
+                    warn!("function failed during streamed response");
+                    record(invocation_outcome(&status).unwrap());
+                    sender.abort();
+                    return;
+                }
+                _ => continue,
+            }
+        }
+    });
+    build_response(status, headers, body)
+}
+
+fn build_response(status: u16, headers: Vec<(String, String)>, body: Body) -> Response<Body> {
+    let mut builder = Response::builder().status(status);
+    for (name, value) in headers {
+        match (HeaderName::from_str(&name), HeaderValue::from_str(&value)) {
+            (Ok(name), Ok(value)) => builder = builder.header(name, value),
+            _ => warn!("dropping invalid response header {:?}", name),
+        }
+    }
+    builder.body(body).unwrap_or_else(|err| {
+        error!("invalid function response: {}", err.to_string());
+        Response::builder()
+            .status(500)
+            .body(Body::from("Invalid function response"))
+            .unwrap()
+    })
+}
+
+/// Map a response passed to `asml-rt.success`. Functions built before `asml-rt.respond` pass
+/// an API Gateway-style JSON object when responding to HTTP requests; any other body is
+/// returned as-is.
+fn legacy_response(response: Vec<u8>) -> Response<Body> {
+    let json = match serde_json::from_slice::<serde_json::Value>(&response) {
+        Ok(json) => json,
+        Err(_) => {
+            return Response::builder()
+                .status(200)
+                .body(Body::from(response))
+                .unwrap()
+        }
+    };
+    if json.get("isBase64Encoded").is_none() {
+        return Response::builder()
+            .status(200)
+            .header("content-type", "application/json")
+            .body(Body::from(response))
+            .unwrap();
+    }
+
+    debug!("function response detected as Lambda-APIGW format");
+    let body = json.get("body").and_then(|b| b.as_str()).unwrap_or_default();
+    let body = match json.get("isBase64Encoded").and_then(|b| b.as_bool()) {
+        Some(true) => match base64::decode(body) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("could not decode function response body: {}", err.to_string());
+                return Response::builder()
+                    .status(500)
+                    .body(Body::from("Invalid function response"))
+                    .unwrap();
+            }
+        },
+        _ => body.as_bytes().to_vec(),
+    };
+    let headers = json
+        .get("headers")
+        .and_then(|h| h.as_object())
+        .map(|h| {
+            h.iter()
+                .filter_map(|(k, v)| Some((k.clone(), v.as_str()?.to_string())))
+                .collect()
+        })
+        .unwrap_or_default();
+    let status = json
+        .get("statusCode")
+        .and_then(|s| s.as_u64())
+        .and_then(|s| u16::try_from(s).ok())
+        .unwrap_or(200);
+
+    http_response(asml_rt::HttpResponse {
+        status,
+        headers,
+        body,
+    })
+}
+
+/// True if `part` of a function's coordinates can't reach outside of its project's directory
+fn is_coordinate(part: &str) -> bool {
+    !part.is_empty()
+        && part
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Parse a list of `key=value` pairs separated by commas
+fn parse_map(vars: &String) -> Result<BTreeMap<String, String>, InvocationError> {
     let mut map = BTreeMap::<String, String>::new();
     let pairs = vars.split(',');
     for pair in pairs {
-        let kv = pair.split('=').collect::<Vec<&str>>();
-        map.insert(kv[0].into(), kv[1].into());
+        match pair.split_once('=') {
+            Some((k, v)) if !k.is_empty() => map.insert(k.into(), v.into()),
+            _ => return Err(InvocationError::BadRequest(format!("malformed pair {:?}", pair))),
+        };
     }
-    map
+    Ok(map)
 }
 
 #[derive(Serialize, Deserialize)]
@@ -285,3 +548,92 @@ struct LauncherRequest {
     body_encoding: String,
     body: Option<String>,
 }
+
+#[cfg(test)]
+mod tests {
+    use hyper::{Body, Request};
+    use tokio::sync::mpsc;
+
+    use assemblylift_core::wasm::{status_channel, InvocationError};
+
+    use crate::metrics::INVOCATIONS;
+    use crate::Status;
+
+    use super::{is_coordinate, parse_map, stream_response, try_launch, REFUSED_HEADERS};
+
+    #[test]
+    fn test_is_coordinate() {
+        assert!(is_coordinate("my-service_2"));
+        assert!(!is_coordinate(""));
+        assert!(!is_coordinate("proj/../../etc"));
+        assert!(!is_coordinate("C:\\fn"));
+    }
+
+    #[test]
+    fn test_parse_map() {
+        let map = parse_map(&"A=1,B=x=y".to_string()).unwrap();
+        assert_eq!(Some("1"), map.get("A").map(String::as_str));
+        assert_eq!(Some("x=y"), map.get("B").map(String::as_str));
+        assert!(matches!(parse_map(&"A".to_string()), Err(InvocationError::BadRequest(_))));
+        assert!(matches!(parse_map(&"=1".to_string()), Err(InvocationError::BadRequest(_))));
+    }
+
+    #[tokio::test]
+    async fn test_refused_headers() {
+        for header in REFUSED_HEADERS {
+            let (runner_tx, mut runner_rx) = mpsc::channel(1);
+            let req = Request::builder()
+                .header("x-assemblylift-function-coordinates", "project.service.function")
+                .header(*header, "/=/")
+                .body(Body::empty())
+                .unwrap();
+            let result = try_launch(req, runner_tx).await;
+            assert!(matches!(result, Err(InvocationError::BadRequest(_))), "{} was accepted", header);
+            // Nothing is run for the request
+            assert!(runner_rx.try_recv().is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_coordinates_required() {
+        let (runner_tx, _runner_rx) = mpsc::channel(1);
+        let req = Request::builder().body(Body::empty()).unwrap();
+        assert!(matches!(
+            try_launch(req, runner_tx).await,
+            Err(InvocationError::BadRequest(_))
+        ));
+
+        let (runner_tx, _runner_rx) = mpsc::channel(1);
+        let req = Request::builder()
+            .header("x-assemblylift-function-coordinates", "proj/../...service.function")
+            .body(Body::empty())
+            .unwrap();
+        assert!(matches!(
+            try_launch(req, runner_tx).await,
+            Err(InvocationError::BadRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_streamed_outcome() {
+        let outcome =
+            |function: &str, outcome: &str| INVOCATIONS.with_label_values(&[function, outcome]).get();
+
+        // Recorded once the guest finishes the response, not when it begins it
+        let (status_tx, status_rx) = status_channel::<Status>(4);
+        status_tx.send(Status::Chunk(b"hello".to_vec())).unwrap();
+        status_tx.send(Status::FinishResponse).unwrap();
+        let response = stream_response(200, Vec::new(), status_rx, "test.stream.finished".into());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        assert_eq!(&b"hello"[..], &body[..]);
+        assert_eq!(1, outcome("test.stream.finished", "success"));
+
+        let (status_tx, status_rx) = status_channel::<Status>(4);
+        status_tx.send(Status::Chunk(b"hello".to_vec())).unwrap();
+        status_tx.send(Status::Timeout).unwrap();
+        let response = stream_response(200, Vec::new(), status_rx, "test.stream.timeout".into());
+        assert!(hyper::body::to_bytes(response.into_body()).await.is_err());
+        assert_eq!(0, outcome("test.stream.timeout", "success"));
+        assert_eq!(1, outcome("test.stream.timeout", "timeout"));
+    }
+}
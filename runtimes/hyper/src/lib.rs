@@ -1,5 +1,6 @@
 use std::sync::{Arc, Mutex};
 
+use assemblylift_core::wasm::{asml_rt, scratch};
 use assemblylift_core_iomod::registry::RegistryTx;
 
 use crate::launcher::Launcher;
@@ -7,6 +8,8 @@ use crate::runner::Runner;
 
 pub mod abi;
 pub mod launcher;
+pub mod metrics;
+pub mod reactor_pool;
 pub mod runner;
 
 #[derive(Debug, Clone)]
@@ -14,11 +17,18 @@ pub enum Status {
     Exited(i32),
     Success(Vec<u8>),
     Failure(Vec<u8>),
+    Respond(asml_rt::HttpResponse),
+    BeginResponse(u16, Vec<(String, String)>),
+    Chunk(Vec<u8>),
+    FinishResponse,
+    Timeout,
+    /// The invocation couldn't be started; carries the HTTP status code & message
+    Error(u16, String),
 }
 
 pub fn spawn_runtime(registry_tx: RegistryTx) {
-    // Mapped to /tmp inside the WASM module
-    std::fs::create_dir_all("/tmp/asmltmp").expect("could not create /tmp/asmltmp");
+    // Holds the per-invocation directories mapped to /tmp inside the WASM module
+    scratch::init_root().expect("could not create scratch directory root");
 
     crossbeam_utils::thread::scope(|s| {
         let runner = Arc::new(Mutex::new(Runner::<Status>::new(registry_tx)));
@@ -31,6 +41,10 @@ pub fn spawn_runtime(registry_tx: RegistryTx) {
             let mut launcher = Launcher::new();
             launcher.spawn(tx);
         });
+
+        if let Some(port) = *metrics::METRICS_PORT {
+            s.spawn(move |_| metrics::spawn(port));
+        }
     })
     .unwrap();
 }
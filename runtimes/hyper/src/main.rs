@@ -2,8 +2,11 @@ use std::str::FromStr;
 
 use clap::crate_version;
 use tracing::{info, Level};
+use tracing_subscriber::layer::SubscriberExt;
 use tracing_subscriber::FmtSubscriber;
 
+use assemblylift_core::telemetry;
+
 use assemblylift_core_iomod::registry;
 use assemblylift_core_iomod::registry::registry_channel;
 use assemblylift_hyper_runtime::spawn_runtime;
@@ -17,7 +20,16 @@ fn main() {
             .unwrap_or(&default_level),
     )
     .unwrap_or(Level::INFO);
-    let subscriber = FmtSubscriber::builder().with_max_level(log_level).finish();
+    // Spans are exported from their own runtime, since the launcher & runner each run their own
+    let telemetry_runtime = tokio::runtime::Runtime::new().expect("could not start telemetry runtime");
+    let otlp_layer = {
+        let _guard = telemetry_runtime.enter();
+        telemetry::otlp_layer("assemblylift-hyper-runtime").expect("could not start OTLP exporter")
+    };
+    let subscriber = FmtSubscriber::builder()
+        .with_max_level(log_level)
+        .finish()
+        .with(otlp_layer);
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
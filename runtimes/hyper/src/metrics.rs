@@ -0,0 +1,226 @@
+//! Prometheus metrics for the runtime, served separately from functions so that they can be
+//! scraped without being exposed on the function port. Metrics recorded by `assemblylift_core`
+//! (IOmod calls) and the IOmod registry are registered in the same default registry.
+
+use std::net::SocketAddr;
+use std::path::Path;
+
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Request, Response, Server};
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, register_int_gauge_vec, Encoder, HistogramVec,
+    IntCounterVec, IntGaugeVec, TextEncoder,
+};
+use tracing::{error, info};
+
+use assemblylift_core::wasm::cache::CacheStats;
+use assemblylift_core_iomod::registry::{REGISTRY_CONNECTIONS, REGISTRY_IOMODS};
+
+/// Port serving `/metrics`; set `ASML_METRICS_PORT` empty to disable the endpoint
+pub static METRICS_PORT: Lazy<Option<u16>> = Lazy::new(|| match std::env::var("ASML_METRICS_PORT") {
+    Ok(port) if port.is_empty() => None,
+    Ok(port) => port.parse::<u16>().ok(),
+    Err(_) => Some(9543),
+});
+
+/// Completed invocations, by function & outcome (`success`, `failure`, `timeout` or `error`, or
+/// `cancelled` if the client went away during a streamed response)
+pub static INVOCATIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asml_function_invocations_total",
+        "Function invocations by outcome",
+        &["function", "outcome"]
+    )
+    .unwrap()
+});
+
+/// Time spent in each phase of an invocation: `load` (compiling or deserializing the component),
+/// `link` (creating the store & instantiating), and `run`
+pub static INVOCATION_SECONDS: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "asml_function_invocation_duration_seconds",
+        "Function invocation latency by phase",
+        &["function", "phase"]
+    )
+    .unwrap()
+});
+
+/// Lookups of loaded function components, by result (`hit` or `miss`)
+pub static MODULE_CACHE: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asml_module_cache_lookups_total",
+        "Loaded function component lookups",
+        &["function", "result"]
+    )
+    .unwrap()
+});
+
+/// Lookups in a function's object cache, by result (`hit` or `miss`)
+pub static OBJECT_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asml_object_cache_lookups_total",
+        "Function object cache lookups",
+        &["function", "result"]
+    )
+    .unwrap()
+});
+
+/// Objects dropped from a function's object cache, by reason (`expired` or `evicted`)
+pub static OBJECT_CACHE_REMOVALS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "asml_object_cache_removals_total",
+        "Objects dropped from function object caches",
+        &["function", "reason"]
+    )
+    .unwrap()
+});
+
+/// Objects held by a function's object cache
+pub static OBJECT_CACHE_ENTRIES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "asml_object_cache_entries",
+        "Objects held by function object caches",
+        &["function"]
+    )
+    .unwrap()
+});
+
+/// Encoded size of the objects held by a function's object cache
+pub static OBJECT_CACHE_BYTES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "asml_object_cache_bytes",
+        "Encoded size of the objects held by function object caches",
+        &["function"]
+    )
+    .unwrap()
+});
+
+/// Record the usage of a function's object cache. The cache counts since the function was loaded,
+/// so the counters are advanced to its totals.
+pub fn observe_object_cache(function: &str, stats: CacheStats) {
+    let advance = |counter: &IntCounterVec, label: &str, total: u64| {
+        let counter = counter.with_label_values(&[function, label]);
+        counter.inc_by(total.saturating_sub(counter.get()));
+    };
+    advance(&OBJECT_CACHE_LOOKUPS, "hit", stats.hits);
+    advance(&OBJECT_CACHE_LOOKUPS, "miss", stats.misses);
+    advance(&OBJECT_CACHE_REMOVALS, "expired", stats.expirations);
+    advance(&OBJECT_CACHE_REMOVALS, "evicted", stats.evictions);
+    OBJECT_CACHE_ENTRIES
+        .with_label_values(&[function])
+        .set(stats.entries as i64);
+    OBJECT_CACHE_BYTES
+        .with_label_values(&[function])
+        .set(stats.bytes as i64);
+}
+
+/// Label of functions which aren't installed
+pub const UNKNOWN_FUNCTION: &str = "unknown";
+
+/// Functions are labelled by their coordinates. The coordinates come from the request, so those
+/// of functions which aren't installed share one label rather than each adding a series.
+pub fn function_label(coordinates: &Option<String>, wasm_path: &Path) -> String {
+    match coordinates {
+        Some(coords) if wasm_path.is_file() => coords.clone(),
+        _ => UNKNOWN_FUNCTION.to_string(),
+    }
+}
+
+
+pub fn spawn(port: u16) {
+    // Registered up front, so that they are scraped before the first invocation
+    Lazy::force(&INVOCATIONS);
+    Lazy::force(&INVOCATION_SECONDS);
+    Lazy::force(&MODULE_CACHE);
+    Lazy::force(&OBJECT_CACHE_LOOKUPS);
+    Lazy::force(&OBJECT_CACHE_REMOVALS);
+    Lazy::force(&OBJECT_CACHE_ENTRIES);
+    Lazy::force(&OBJECT_CACHE_BYTES);
+    Lazy::force(&REGISTRY_CONNECTIONS);
+    Lazy::force(&REGISTRY_IOMODS);
+
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .build()
+        .unwrap();
+    runtime.block_on(async {
+        let make_svc = make_service_fn(|_| async {
+            Ok::<_, anyhow::Error>(service_fn(|req| async { serve(req) }))
+        });
+
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        info!("Serving metrics from {}", addr.to_string());
+        if let Err(e) = Server::bind(&addr).serve(make_svc).await {
+            error!("metrics server error: {}", e);
+        }
+    });
+}
+
+fn serve(req: Request<Body>) -> anyhow::Result<Response<Body>> {
+    if req.uri().path() != "/metrics" {
+        return Ok(Response::builder().status(404).body(Body::default())?);
+    }
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder.encode(&prometheus::gather(), &mut buffer)?;
+    Ok(Response::builder()
+        .status(200)
+        .header("content-type", encoder.format_type())
+        .body(Body::from(buffer))?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use prometheus::proto::MetricFamily;
+    use uuid::Uuid;
+
+    use super::{function_label, INVOCATIONS, INVOCATION_SECONDS, MODULE_CACHE, UNKNOWN_FUNCTION};
+
+    fn family(name: &str) -> Option<MetricFamily> {
+        prometheus::gather().into_iter().find(|family| family.get_name() == name)
+    }
+
+    #[test]
+    fn test_function_label() {
+        let wasm_path = std::env::temp_dir().join(format!("asml-metrics-{}.wasm", Uuid::new_v4()));
+        let coordinates = Some("project.service.function".to_string());
+        assert_eq!(UNKNOWN_FUNCTION, function_label(&coordinates, &wasm_path));
+        assert_eq!(UNKNOWN_FUNCTION, function_label(&None, Path::new("/")));
+
+        std::fs::write(&wasm_path, b"").unwrap();
+        assert_eq!("project.service.function", function_label(&coordinates, &wasm_path));
+        std::fs::remove_file(&wasm_path).unwrap();
+    }
+
+    #[test]
+    fn test_registry() {
+        INVOCATIONS.with_label_values(&["test.metrics.registry", "success"]).inc();
+
+        let invocations = family("asml_function_invocations_total").unwrap();
+        let metric = invocations
+            .get_metric()
+            .iter()
+            .find(|metric| {
+                metric.get_label().iter().any(|label| {
+                    label.get_name() == "function" && label.get_value() == "test.metrics.registry"
+                })
+            })
+            .unwrap();
+        let labels = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name(), label.get_value()))
+            .collect::<Vec<_>>();
+        assert_eq!(vec![("function", "test.metrics.registry"), ("outcome", "success")], labels);
+        assert_eq!(1.0, metric.get_counter().get_value());
+
+        // Vectors are only gathered once they have a series
+        INVOCATION_SECONDS.with_label_values(&["test.metrics.registry", "run"]).observe(0.1);
+        MODULE_CACHE.with_label_values(&["test.metrics.registry", "hit"]).inc();
+        assert!(family("asml_function_invocation_duration_seconds").is_some());
+        assert!(family("asml_module_cache_lookups_total").is_some());
+    }
+}
@@ -0,0 +1,247 @@
+//! Warm reactor instances, kept between invocations of the same function.
+//!
+//! An instance is only reused for an invocation with the same configuration it was linked with, so
+//! instances are keyed by everything which goes into linking one. The pool holds a bounded number of
+//! idle instances; the least recently used is evicted to make room, and instances idle for longer
+//! than the pool's idle timeout are dropped.
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+
+use assemblylift_core::threader::IomodVersions;
+use assemblylift_core::wasm::capabilities::Capabilities;
+
+/// Maximum number of idle reactor instances kept across all functions
+pub static MAX_IDLE_REACTORS: Lazy<usize> = Lazy::new(|| {
+    std::env::var("ASML_MAX_IDLE_REACTORS")
+        .ok()
+        .and_then(|max| max.parse().ok())
+        .unwrap_or(32)
+});
+
+/// How long a reactor instance may stay idle before it is dropped
+pub static REACTOR_IDLE_TIMEOUT: Lazy<Duration> = Lazy::new(|| {
+    Duration::from_secs(
+        std::env::var("ASML_REACTOR_IDLE_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(300),
+    )
+});
+
+/// The configuration a reactor instance was linked with
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ReactorKey {
+    pub wasm_path: PathBuf,
+    pub runtime_environment: String,
+    pub env_vars: Vec<(String, String)>,
+    pub bind_paths: Vec<(String, String)>,
+    /// The function's capabilities, as JSON
+    pub capabilities: String,
+    pub timeout: Option<Duration>,
+    pub memory_size_mb: Option<u32>,
+    pub tmp_quota_mb: Option<u32>,
+    pub iomod_timeout: Option<Duration>,
+    pub iomod_versions: Vec<(String, String)>,
+}
+
+impl ReactorKey {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        wasm_path: PathBuf,
+        runtime_environment: String,
+        env_vars: &[(String, String)],
+        bind_paths: &[(String, String)],
+        capabilities: &Capabilities,
+        timeout: Option<Duration>,
+        memory_size_mb: Option<u32>,
+        tmp_quota_mb: Option<u32>,
+        iomod_timeout: Option<Duration>,
+        iomod_versions: &IomodVersions,
+    ) -> Self {
+        let mut env_vars = env_vars.to_vec();
+        env_vars.sort();
+        let mut bind_paths = bind_paths.to_vec();
+        bind_paths.sort();
+        let mut iomod_versions: Vec<(String, String)> = iomod_versions
+            .iter()
+            .map(|(coords, version)| (coords.clone(), version.clone()))
+            .collect();
+        iomod_versions.sort();
+        Self {
+            wasm_path,
+            runtime_environment,
+            env_vars,
+            bind_paths,
+            capabilities: serde_json::to_string(capabilities).unwrap_or_default(),
+            timeout,
+            memory_size_mb,
+            tmp_quota_mb,
+            iomod_timeout,
+            iomod_versions,
+        }
+    }
+}
+
+pub struct ReactorPool<K, T> {
+    idle: BTreeMap<K, Vec<(Instant, T)>>,
+    max_idle: usize,
+    idle_timeout: Duration,
+}
+
+impl<K: Ord + Clone, T> ReactorPool<K, T> {
+    pub fn new(max_idle: usize, idle_timeout: Duration) -> Self {
+        Self {
+            idle: BTreeMap::new(),
+            max_idle,
+            idle_timeout,
+        }
+    }
+
+    /// Take the most recently used idle instance for `key`
+    pub fn take(&mut self, key: &K) -> Option<T> {
+        self.evict_expired();
+        let instances = self.idle.get_mut(key)?;
+        let instance = instances.pop().map(|(_, instance)| instance);
+        if instances.is_empty() {
+            self.idle.remove(key);
+        }
+        instance
+    }
+
+    /// Return an instance to the pool once its invocation has completed
+    pub fn put(&mut self, key: K, instance: T) {
+        if self.max_idle == 0 {
+            return;
+        }
+        self.evict_expired();
+        while self.len() >= self.max_idle {
+            self.evict_least_recently_used();
+        }
+        self.idle.entry(key).or_default().push((Instant::now(), instance));
+    }
+
+    /// Drop instances which have been idle for longer than the idle timeout
+    pub fn evict_expired(&mut self) {
+        let idle_timeout = self.idle_timeout;
+        self.idle.retain(|_, instances| {
+            instances.retain(|(since, _)| since.elapsed() < idle_timeout);
+            !instances.is_empty()
+        });
+    }
+
+    /// The number of idle instances in the pool
+    pub fn len(&self) -> usize {
+        self.idle.values().map(|instances| instances.len()).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.idle.is_empty()
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        // Each key's instances are in the order they were returned, so the oldest is first
+        let oldest = self
+            .idle
+            .iter()
+            .filter_map(|(key, instances)| instances.first().map(|(since, _)| (*since, key.clone())))
+            .min_by_key(|(since, _)| *since)
+            .map(|(_, key)| key);
+        if let Some(key) = oldest {
+            let instances = self.idle.get_mut(&key).unwrap();
+            instances.remove(0);
+            if instances.is_empty() {
+                self.idle.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use assemblylift_core::wasm::capabilities::Capabilities;
+
+    use super::{ReactorKey, ReactorPool};
+
+    fn key(env_vars: &[(String, String)], iomod_versions: &HashMap<String, String>) -> ReactorKey {
+        ReactorKey::new(
+            PathBuf::from("/opt/fn.component.wasm"),
+            "default".to_string(),
+            env_vars,
+            &[],
+            &Capabilities::default(),
+            None,
+            Some(64),
+            None,
+            None,
+            iomod_versions,
+        )
+    }
+
+    #[test]
+    fn test_key() {
+        let a = ("A".to_string(), "1".to_string());
+        let b = ("B".to_string(), "2".to_string());
+        let versions = HashMap::from([("akkoro.std.http".to_string(), "^1".to_string())]);
+        // Order doesn't matter, but every part of the configuration does
+        assert_eq!(
+            key(&[a.clone(), b.clone()], &versions),
+            key(&[b.clone(), a.clone()], &versions)
+        );
+        assert_ne!(key(&[a.clone()], &versions), key(&[b.clone()], &versions));
+        assert_ne!(key(&[a.clone()], &versions), key(&[a.clone()], &HashMap::new()));
+
+        let mut other = key(&[a.clone()], &versions);
+        other.memory_size_mb = Some(128);
+        assert_ne!(key(&[a.clone()], &versions), other);
+    }
+
+    #[test]
+    fn test_take() {
+        let mut pool = ReactorPool::new(4, Duration::from_secs(60));
+        pool.put("a", 1);
+        pool.put("a", 2);
+        pool.put("b", 3);
+        assert_eq!(Some(2), pool.take(&"a"));
+        assert_eq!(Some(1), pool.take(&"a"));
+        assert_eq!(None, pool.take(&"a"));
+        assert_eq!(Some(3), pool.take(&"b"));
+        assert_eq!(0, pool.len());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let mut pool = ReactorPool::new(2, Duration::from_secs(60));
+        pool.put("a", 1);
+        pool.put("b", 2);
+        pool.put("c", 3);
+        assert_eq!(2, pool.len());
+        assert_eq!(None, pool.take(&"a"));
+        assert_eq!(Some(2), pool.take(&"b"));
+        assert_eq!(Some(3), pool.take(&"c"));
+    }
+
+    #[test]
+    fn test_evicts_expired() {
+        let mut pool = ReactorPool::new(4, Duration::from_millis(10));
+        pool.put("a", 1);
+        std::thread::sleep(Duration::from_millis(20));
+        pool.evict_expired();
+        assert_eq!(0, pool.len());
+        assert_eq!(None, pool.take(&"a"));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut pool = ReactorPool::new(0, Duration::from_secs(60));
+        pool.put("a", 1);
+        assert_eq!(None, pool.take(&"a"));
+    }
+}
@@ -2,16 +2,35 @@ use std::cell::RefCell;
 use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::rc::Rc;
+use std::time::{Duration, Instant};
 
+use once_cell::sync::Lazy;
 use tokio::sync::mpsc;
-use tracing::{debug, info};
+use tracing::{debug, error, info, info_span, Instrument};
+use tracing_opentelemetry::OpenTelemetrySpanExt;
 
-use assemblylift_core::wasm::{StatusTx, Wasmtime};
+use assemblylift_core::threader::IomodVersions;
+use assemblylift_core::{runtime_environment, telemetry};
+use assemblylift_core::wasm::capabilities::Capabilities;
+use assemblylift_core::wasm::{
+    is_limit_exceeded, is_timeout, Entrypoint, FunctionLimits, FunctionStore, HandlerFunc,
+    InvocationError, StatusTx, Wasmtime,
+};
 use assemblylift_core_iomod::registry::RegistryTx;
 
 use crate::abi::Abi;
+use crate::metrics::{function_label, observe_object_cache, INVOCATION_SECONDS, MODULE_CACHE};
+use crate::reactor_pool::{ReactorKey, ReactorPool, MAX_IDLE_REACTORS, REACTOR_IDLE_TIMEOUT};
 use crate::Status;
 
+/// Directory holding interpreter assets, e.g. `ruby-wasm32-wasi`
+pub static INTERPRETER_ROOT: Lazy<PathBuf> = Lazy::new(|| {
+    PathBuf::from(std::env::var("ASML_INTERPRETER_ROOT").unwrap_or("/usr/bin".to_string()))
+});
+
+/// A warm reactor instance, waiting for its next invocation
+type IdleReactor = (HandlerFunc, FunctionStore<Abi, Status>);
+
 pub type RunnerTx<S> = mpsc::Sender<RunnerMessage<S>>;
 pub type RunnerRx<S> = mpsc::Receiver<RunnerMessage<S>>;
 pub type RunnerChannel<S> = (RunnerTx<S>, RunnerRx<S>);
@@ -27,6 +46,18 @@ where
     pub env_vars: BTreeMap<String, String>,
     pub bind_paths: BTreeMap<String, String>,
     pub runtime_environment: Option<String>,
+    pub request_id: String,
+    /// The function's `project.service.function` coordinates, if known
+    pub coordinates: Option<String>,
+    /// W3C trace context of the launcher's span for this invocation
+    pub traceparent: Option<String>,
+    pub capabilities: Capabilities,
+    pub timeout: Option<Duration>,
+    pub memory_size_mb: Option<u32>,
+    pub tmp_quota_mb: Option<u32>,
+    pub iomod_timeout: Option<Duration>,
+    /// Version requirements of the function's IOmod dependencies
+    pub iomod_versions: IomodVersions,
 }
 
 pub struct Runner<S>
@@ -45,6 +76,7 @@ impl Runner<Status> {
             registry_tx,
             runtime: tokio::runtime::Builder::new_current_thread()
                 .enable_io()
+                .enable_time()
                 .build()
                 .unwrap(),
         }
@@ -55,6 +87,22 @@ impl Runner<Status> {
         tokio::task::LocalSet::new().block_on(&self.runtime, async {
             let mut functions: BTreeMap<PathBuf, Rc<RefCell<Wasmtime<Abi, Status>>>> =
                 BTreeMap::new();
+            // Reactor instances are returned here after each invocation which completes normally
+            let idle_reactors: Rc<RefCell<ReactorPool<ReactorKey, IdleReactor>>> = Rc::new(
+                RefCell::new(ReactorPool::new(*MAX_IDLE_REACTORS, *REACTOR_IDLE_TIMEOUT)),
+            );
+            {
+                // Drop instances which have been idle too long, even if their functions aren't called again
+                let idle_reactors = idle_reactors.clone();
+                tokio::task::spawn_local(async move {
+                    let period = (*REACTOR_IDLE_TIMEOUT / 2).max(Duration::from_secs(1));
+                    let mut interval = tokio::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        idle_reactors.borrow_mut().evict_expired();
+                    }
+                });
+            }
 
             while let Some(msg) = self.channel.1.recv().await {
                 debug!("received runner message");
@@ -66,6 +114,9 @@ impl Runner<Status> {
 
                 let wasm_path = msg.wasm_path;
                 info!("Loading module at {}", wasm_path.clone().display());
+                let function = function_label(&msg.coordinates, &wasm_path);
+                let span = info_span!("run_function", function = %function, request_id = %msg.request_id);
+                span.set_parent(telemetry::context_from(msg.traceparent.as_deref()));
 
                 // Environment vars prefixed with __ASML_ are defined in the function definition;
                 // the prefix indicates that they are to be mapped to the function environment.
@@ -88,7 +139,7 @@ impl Runner<Status> {
                     )
                 );
 
-                let bind_paths: Vec<(String, String)> = Vec::from_iter(
+                let mut bind_paths: Vec<(String, String)> = Vec::from_iter(
                     msg
                     .bind_paths
                     .into_iter()
@@ -96,44 +147,152 @@ impl Runner<Status> {
                     .into_iter(),
                 );
 
-                let wasmtime = match functions.contains_key(&*wasm_path) {
-                    false => {
-                        let wt = Rc::new(RefCell::new(
-                            // FIXME this should return an error response via status_sender instead of panicing
-                            Wasmtime::<Abi, Status>::new_from_path(wasm_path.as_ref())
-                                .expect("could not create WASM runtime from module path"),
-                        ));
-                        functions.insert(wasm_path, wt.clone());
-                        wt
+                // An interpreter's function source sits alongside the function component
+                if let Some(environment) = runtime_environment::by_name(&runtime_environment) {
+                    if let Some(interpreter) = &environment.interpreter {
+                        let source_dir = wasm_path.with_file_name(interpreter.source_dir);
+                        let assets_dir = INTERPRETER_ROOT.join(interpreter.assets);
+                        bind_paths.append(&mut environment.resolve_bind_paths(&source_dir, &assets_dir));
                     }
-                    true => functions.get(&*wasm_path).unwrap().clone(),
+                }
+
+                let wasmtime = match functions.get(&*wasm_path) {
+                    Some(wt) => {
+                        MODULE_CACHE.with_label_values(&[&function, "hit"]).inc();
+                        wt.clone()
+                    }
+                    None => match info_span!(parent: &span, "load").in_scope(|| {
+                        let started = Instant::now();
+                        let wt = Wasmtime::<Abi, Status>::new_from_path(wasm_path.as_ref());
+                        observe(&function, "load", started);
+                        wt
+                    }) {
+                        Ok(wt) => {
+                            MODULE_CACHE.with_label_values(&[&function, "miss"]).inc();
+                            let wt = Rc::new(RefCell::new(wt));
+                            functions.insert(wasm_path.clone(), wt.clone());
+                            wt
+                        }
+                        Err(err) => {
+                            report_invocation_error(&msg.status_sender, err);
+                            continue;
+                        }
+                    },
                 };
 
-                let (command, mut store) = wasmtime
-                    .borrow_mut()
+                // A warm instance is only reused for an invocation with the same configuration
+                let reactor_key = ReactorKey::new(
+                    wasm_path.clone(),
+                    runtime_environment.clone(),
+                    &env_vars,
+                    &bind_paths,
+                    &msg.capabilities,
+                    msg.timeout,
+                    msg.memory_size_mb,
+                    msg.tmp_quota_mb,
+                    msg.iomod_timeout,
+                    &msg.iomod_versions,
+                );
+                let idle = idle_reactors.borrow_mut().take(&reactor_key);
+                // An instance whose /tmp can't be emptied is dropped, and a fresh one linked instead
+                let idle = idle.and_then(|(handler, mut store)| {
+                    match store.data_mut().begin_invocation(
+                        msg.status_sender.clone(),
+                        Some(msg.request_id.clone()),
+                        msg.input.clone(),
+                        msg.timeout,
+                    ) {
+                        Ok(()) => Some((handler, store)),
+                        Err(err) => {
+                            error!("could not reset warm instance: {}", err.to_string());
+                            None
+                        }
+                    }
+                });
+                if let Some((handler, mut store)) = idle {
+                    debug!("reusing warm instance of {}", wasm_path.display());
+                    let status_sender = msg.status_sender.clone();
+                    let coordinates = msg.coordinates;
+                    let wasmtime = wasmtime.clone();
+                    let idle_reactors = idle_reactors.clone();
+                    tokio::task::spawn_local(async move {
+                        let started = Instant::now();
+                        let result = wasmtime
+                            .borrow()
+                            .call_handler(&handler, &mut store)
+                            .instrument(info_span!(parent: &span, "run"))
+                            .await;
+                        observe(&function, "run", started);
+                        observe_object_cache(&function, wasmtime.borrow().cache_stats());
+                        if report_result(&status_sender, &store, result, coordinates) {
+                            idle_reactors.borrow_mut().put(reactor_key, (handler, store));
+                        }
+                    });
+                    continue;
+                }
+
+                let started = Instant::now();
+                let linked = wasmtime
+                    .borrow()
                     .link_wasi_component(
                         self.registry_tx.clone(),
                         msg.status_sender.clone(),
                         env_vars,
                         runtime_environment.clone(),
                         bind_paths,
-                        None,
+                        &msg.capabilities,
+                        FunctionLimits {
+                            timeout: msg.timeout,
+                            memory_size_mb: msg.memory_size_mb,
+                            tmp_quota_mb: msg.tmp_quota_mb,
+                            iomod_timeout: msg.iomod_timeout,
+                        },
+                        msg.iomod_versions.clone(),
+                        Some(msg.request_id.clone()),
                         &msg.input,
                     )
-                    .await
-                    .expect("could not link wasm component");
+                    .instrument(info_span!(parent: &span, "link"))
+                    .await;
+                observe(&function, "link", started);
+                let (entrypoint, mut store) = match linked {
+                    Ok(linked) => linked,
+                    Err(err) => {
+                        report_invocation_error(&msg.status_sender, err);
+                        continue;
+                    }
+                };
 
                 let wasmtime = wasmtime.clone();
+                let idle_reactors = idle_reactors.clone();
                 tokio::task::spawn_local(async move {
-                    match wasmtime
-                        .borrow_mut()
-                        .run_component(command, &mut store)
-                        .await
-                    {
-                        Ok(_) => msg.status_sender.send(Status::Exited(0)),
-                        Err(err) => msg.status_sender.send(Status::Failure(
-                            format!("WASM module exited in error: {}", err.to_string()).as_bytes().to_vec(),
-                        )),
+                    let started = Instant::now();
+                    match entrypoint {
+                        Entrypoint::Command(command) => {
+                            let result = wasmtime
+                                .borrow()
+                                .run_component(command, &mut store)
+                                .instrument(info_span!(parent: &span, "run"))
+                                .await;
+                            observe(&function, "run", started);
+                            observe_object_cache(&function, wasmtime.borrow().cache_stats());
+                            if report_result(&msg.status_sender, &store, result, msg.coordinates) {
+                                if let Err(err) = msg.status_sender.send(Status::Exited(0)) {
+                                    error!("could not send status: {}", err.to_string());
+                                }
+                            }
+                        }
+                        Entrypoint::Reactor(handler) => {
+                            let result = wasmtime
+                                .borrow()
+                                .call_handler(&handler, &mut store)
+                                .instrument(info_span!(parent: &span, "run"))
+                                .await;
+                            observe(&function, "run", started);
+                            observe_object_cache(&function, wasmtime.borrow().cache_stats());
+                            if report_result(&msg.status_sender, &store, result, msg.coordinates) {
+                                idle_reactors.borrow_mut().put(reactor_key, (handler, store));
+                            }
+                        }
                     }
                 });
             }
@@ -144,3 +303,38 @@ impl Runner<Status> {
         self.channel.0.clone()
     }
 }
+
+/// Report a failed invocation to the launcher. Returns true if the invocation succeeded, in which case
+/// its response has already been sent, and a reactor instance may be reused.
+fn report_result(
+    status_sender: &StatusTx<Status>,
+    store: &FunctionStore<Abi, Status>,
+    result: anyhow::Result<()>,
+    coordinates: Option<String>,
+) -> bool {
+    let status = match result {
+        Ok(_) => return true,
+        Err(err) if is_timeout(&err) => Status::Timeout,
+        // The failure has already been reported to the launcher by the limiter
+        Err(err) if is_limit_exceeded(&err) => return false,
+        Err(err) => Status::Failure(store.data().crash_body(&err, coordinates)),
+    };
+    if let Err(err) = status_sender.send(status) {
+        error!("could not send status: {}", err.to_string());
+    }
+    false
+}
+
+fn observe(function: &str, phase: &str, started: Instant) {
+    INVOCATION_SECONDS
+        .with_label_values(&[function, phase])
+        .observe(started.elapsed().as_secs_f64());
+}
+
+/// Report an invocation which could not be started to the launcher
+fn report_invocation_error(status_sender: &StatusTx<Status>, err: InvocationError) {
+    error!("{}", err.to_string());
+    if let Err(err) = status_sender.send(Status::Error(err.status_code(), err.to_string())) {
+        error!("could not send status: {}", err.to_string());
+    }
+}